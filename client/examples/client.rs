@@ -1,4 +1,5 @@
-use client::{Connection, Flags};
+use client::{Connection, Flags, TransactionMode};
+use futures_util::StreamExt;
 use tokio::net::TcpStream;
 
 #[tokio::main]
@@ -6,11 +7,12 @@ async fn main() {
     let stream = TcpStream::connect("localhost:4567").await.unwrap();
     stream.set_nodelay(true).unwrap();
 
+    let user = "";
     let password = "";
     let path = ":memory:";
     let flags = Flags::default();
 
-    let mut client = Connection::connect(stream, password, path, flags)
+    let mut client = Connection::connect(stream, user, password, path, flags)
         .await
         .unwrap();
 
@@ -32,10 +34,53 @@ async fn main() {
         .await
         .unwrap();
 
+    let stmt = client
+        .prepare("insert into test (value) values (?1)")
+        .await
+        .unwrap();
+    for value in ["hello Bird", "hello Fish"] {
+        let changes = stmt.execute(&mut client, [value]).await.unwrap();
+        dbg!(changes.last_insert_rowid);
+    }
+    stmt.close(&mut client).await.unwrap();
+
     let query = client.query("select * from test").await.unwrap();
     dbg!(&query);
 
-    client.execute("delete from test").await.unwrap();
+    let query = client
+        .query_with(
+            "select * from test where value = :value",
+            [(":value", "hello Cat")],
+        )
+        .await
+        .unwrap();
+    dbg!(&query);
+
+    let mut rows = client
+        .query_stream("select * from test where id > ?1", [1])
+        .await
+        .unwrap();
+    while let Some(row) = rows.next().await {
+        dbg!(row.unwrap());
+    }
+    drop(rows);
+
+    let mut tx = client.begin(TransactionMode::Immediate).await.unwrap();
+    tx.execute("update test set value = 'hello Horse' where id = 1")
+        .await
+        .unwrap();
+    tx.savepoint("fish").await.unwrap();
+    tx.execute("delete from test where value = 'hello Fish'")
+        .await
+        .unwrap();
+    tx.rollback_to("fish").await.unwrap();
+    tx.commit().await.unwrap();
+
+    let changes = client
+        .execute_with("delete from test where id > ?1", [0])
+        .await
+        .unwrap();
+    dbg!(changes.rows_affected);
 
     client.disconnect().await.unwrap();
 }
@@ -0,0 +1,21 @@
+use client::{Connection, Flags, Tls};
+use tokio::net::TcpStream;
+
+#[tokio::main]
+async fn main() {
+    // The server certificate, or the CA that issued it
+    let tls = Tls::new("cert.pem", "localhost").unwrap();
+
+    let stream = TcpStream::connect("localhost:4567").await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    let stream = tls.connect(stream).await.unwrap();
+
+    let mut client = Connection::connect(stream, "", "", ":memory:", Flags::default())
+        .await
+        .unwrap();
+
+    let query = client.query("select sqlite_version()").await.unwrap();
+    dbg!(&query);
+
+    client.disconnect().await.unwrap();
+}
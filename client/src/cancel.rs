@@ -0,0 +1,35 @@
+use crate::{Connection, Error, Result};
+use protocol::*;
+use tokio::io::{AsyncRead, AsyncWrite, BufStream};
+
+/// Cancels the commands of a session, see [`Connection::cancel_token`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CancelToken {
+    key: CancelKey,
+    /// Bounds of the session the token cancels
+    limits: DecodeLimits,
+}
+
+impl CancelToken {
+    pub(crate) fn new(key: CancelKey, limits: DecodeLimits) -> Self {
+        Self { key, limits }
+    }
+
+    /// Interrupts the command the session is running, if any, which then fails
+    /// with [`Error::Interrupted`]. `stream` is a new connection to the same server,
+    /// used for the request only.
+    pub async fn cancel<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = BufStream::new(stream);
+
+        let version = read_protocol_version(&mut stream).await?;
+        if version.major != VERSION.major {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        write_request(&mut stream, Request::Cancel(self.key)).await?;
+        Connection::status(&mut stream, &self.limits).await?;
+        Ok(())
+    }
+}
@@ -1,17 +1,98 @@
 use protocol::*;
-pub use protocol::{Column, Error as ProtocolError, Flags, Query, Value, Version, consts::*};
+pub use protocol::{
+    Changes, Column, DbConfig, DecodeLimits, Error as ProtocolError, ErrorClass, ErrorStatus,
+    Fetched, Flags, Limit, Parameters, Params, Query, Script, ScriptError, StatementResult,
+    TransactionMode, TransactionState, Value, Version, consts::*,
+};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, BufStream};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+mod cancel;
+mod options;
+mod stream;
+mod tls;
+mod transaction;
+
+pub use cancel::CancelToken;
+pub use options::ConnectOptions;
+pub use stream::QueryStream;
+pub use tls::Tls;
+pub use tokio_rustls::client::TlsStream;
+pub use transaction::Transaction;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Protocol(#[from] protocol::Error),
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TLS Error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    #[error("Invalid PEM File: {0}")]
+    Pem(#[from] tokio_rustls::rustls::pki_types::pem::Error),
+    #[error("Invalid Server Name: {0}")]
+    InvalidServerName(String),
     #[error("Unsupported Version: {0:?}")]
     UnsupportedVersion(Version),
+    #[error(
+        "Argon2 Params Out Of Bounds: the server asked for m_cost {}, t_cost {}, p_cost {}",
+        .0.m_cost, .0.t_cost, .0.p_cost
+    )]
+    HashParams(Params),
+    #[error("Server Authentication Failed: {0}")]
+    ServerAuth(&'static str),
+    #[error("Authentication Failed: {0}")]
+    Auth(ErrorStatus),
+    #[error("Constraint Violation: {0}")]
+    ConstraintViolation(ErrorStatus),
+    #[error("Database Busy: {0}")]
+    Busy(ErrorStatus),
+    #[error("Read-Only Database: {0}")]
+    ReadOnly(ErrorStatus),
+    #[error("Interrupted: {0}")]
+    Interrupted(ErrorStatus),
+    #[error("Timeout: {0}")]
+    Timeout(ErrorStatus),
     #[error("Response: {0}")]
-    Status(String),
+    Status(ErrorStatus),
     #[error("Only UTF-8 'TEXT' value is supported")]
     InvalidUtf8,
+    #[error("Connection Broken: a query stream was dropped while reading a batch")]
+    Broken,
+}
+
+impl Error {
+    /// The error status responded by the server, if any
+    pub fn status(&self) -> Option<&ErrorStatus> {
+        match self {
+            Error::Auth(status)
+            | Error::ConstraintViolation(status)
+            | Error::Busy(status)
+            | Error::ReadOnly(status)
+            | Error::Interrupted(status)
+            | Error::Timeout(status)
+            | Error::Status(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorStatus> for Error {
+    fn from(status: ErrorStatus) -> Self {
+        match (status.class, status.code) {
+            (ErrorClass::Auth, _) => Error::Auth(status),
+            (ErrorClass::Interrupted, _) => Error::Interrupted(status),
+            (ErrorClass::Timeout, _) => Error::Timeout(status),
+            (ErrorClass::Sqlite, SQLITE_CONSTRAINT) => Error::ConstraintViolation(status),
+            (ErrorClass::Sqlite, SQLITE_BUSY) => Error::Busy(status),
+            (ErrorClass::Sqlite, SQLITE_READONLY) => Error::ReadOnly(status),
+            _ => Error::Status(status),
+        }
+    }
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -19,79 +100,560 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 #[derive(Debug)]
 pub struct Connection<T> {
     stream: BufStream<T>,
+    /// Column count of a query stream dropped before its end
+    unfinished: Option<usize>,
+    /// Set while a query stream reads a batch, left set if the stream
+    /// is dropped part-way, which leaves the connection out of sync
+    broken: bool,
+    /// Set once a transaction is dropped without being committed or rolled back
+    rollback: bool,
+    transaction_state: TransactionState,
+    cancel_key: CancelKey,
+    /// Time limit of query, execute and transaction commands in milliseconds
+    timeout: Option<u64>,
+    /// Bounds on the responses read from the server
+    limits: DecodeLimits,
+}
+
+#[cfg(unix)]
+impl Connection<UnixStream> {
+    /// Connects to a server listening on the Unix domain socket `socket`
+    pub async fn connect_unix<S, U, P, D>(
+        socket: S,
+        user: U,
+        password: P,
+        path: D,
+        flags: Flags,
+    ) -> Result<Self>
+    where
+        S: AsRef<Path>,
+        U: AsRef<str>,
+        P: AsRef<str>,
+        D: AsRef<str>,
+    {
+        Self::connect_unix_with(
+            socket,
+            user,
+            password,
+            path,
+            flags,
+            ConnectOptions::default(),
+        )
+        .await
+    }
+
+    /// Like [`Connection::connect_unix`], with `options`
+    pub async fn connect_unix_with<S, U, P, D>(
+        socket: S,
+        user: U,
+        password: P,
+        path: D,
+        flags: Flags,
+        options: ConnectOptions,
+    ) -> Result<Self>
+    where
+        S: AsRef<Path>,
+        U: AsRef<str>,
+        P: AsRef<str>,
+        D: AsRef<str>,
+    {
+        let stream = UnixStream::connect(socket).await?;
+        Self::connect_with(stream, user, password, path, flags, options).await
+    }
 }
 
 impl<T> Connection<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    pub async fn connect<P: AsRef<str>, D: AsRef<str>>(
+    /// Authenticates as `user` with `password` and opens the database at `path`.
+    /// `user` is ignored by servers with a single password, and may be left empty for them.
+    pub async fn connect<U: AsRef<str>, P: AsRef<str>, D: AsRef<str>>(
         stream: T,
+        user: U,
         password: P,
         path: D,
         flags: Flags,
+    ) -> Result<Self> {
+        Self::connect_with(
+            stream,
+            user,
+            password,
+            path,
+            flags,
+            ConnectOptions::default(),
+        )
+        .await
+    }
+
+    /// Like [`Connection::connect`], with `options`
+    pub async fn connect_with<U: AsRef<str>, P: AsRef<str>, D: AsRef<str>>(
+        stream: T,
+        user: U,
+        password: P,
+        path: D,
+        flags: Flags,
+        options: ConnectOptions,
     ) -> Result<Self> {
         let mut stream = BufStream::new(stream);
 
         let version = read_protocol_version(&mut stream).await?;
-        if version.major != 1 {
+        if version.major != VERSION.major {
             return Err(Error::UnsupportedVersion(version));
         }
+        let mutual = version >= MUTUAL_VERSION;
+        if !mutual && options.require_server_proof {
+            return Err(Error::ServerAuth(
+                "the server can't prove it knows the password",
+            ));
+        }
+        let request = match mutual {
+            true => Request::MutualSession,
+            false => Request::Session,
+        };
+        write_request(&mut stream, request).await?;
 
-        let client_salt = rand_salt();
-        write_salt(&mut stream, client_salt).await?;
+        // A server trusting the client certificate asks for no password
+        let method = read_auth_method(&mut stream).await?;
+        if method == AuthMethod::Certificate && options.require_server_proof {
+            return Err(Error::ServerAuth("the server skipped the password step"));
+        }
+        if method == AuthMethod::Password {
+            write_user(&mut stream, user).await?;
+            let client_salt = rand_salt();
+            write_salt(&mut stream, client_salt).await?;
 
-        let server_salt = read_salt(&mut stream).await?;
-        let params = read_hash_params(&mut stream).await?;
+            let server_salt = read_salt(&mut stream).await?;
+            let params = read_hash_params(&mut stream).await?;
+            options.check(params)?;
 
-        write_auth_password(&mut stream, password, client_salt, server_salt, params).await?;
-        Self::status(&mut stream).await?;
+            let prehashed = match read_prehash(&mut stream).await? {
+                Some(prehash) => {
+                    options.check(prehash.params)?;
+                    Some(to_prehash_password(password.as_ref(), &prehash).await?)
+                }
+                None => None,
+            };
+            let secret = match &prehashed {
+                Some(prehashed) => prehashed.as_slice(),
+                None => password.as_ref().as_bytes(),
+            };
+            let proof = match mutual {
+                true => {
+                    let proof = write_mutual_auth_password(
+                        &mut stream,
+                        secret,
+                        client_salt,
+                        server_salt,
+                        params,
+                    )
+                    .await?;
+                    Some(proof)
+                }
+                false => {
+                    write_auth_password(&mut stream, secret, client_salt, server_salt, params)
+                        .await?;
+                    None
+                }
+            };
+            Self::status(&mut stream, &options.decode_limits).await?;
+            if let Some(proof) = proof
+                && read_server_proof(&mut stream).await? != proof
+            {
+                return Err(Error::ServerAuth("the server doesn't know the password"));
+            }
+        }
 
         write_connect(&mut stream, path, flags).await?;
-        Self::status(&mut stream).await?;
+        Self::status(&mut stream, &options.decode_limits).await?;
+        let cancel_key = read_cancel_key(&mut stream).await?;
 
-        Ok(Self { stream })
+        Ok(Self {
+            stream,
+            cancel_key,
+            timeout: None,
+            limits: options.decode_limits,
+            unfinished: None,
+            broken: false,
+            rollback: false,
+            transaction_state: TransactionState::Idle,
+        })
     }
 
-    async fn status(reader: &mut BufStream<T>) -> Result<()> {
-        read_status(reader)
-            .await?
-            .to_result()
-            .map_err(Error::Status)?;
+    /// A token to cancel the commands of this session from another connection
+    pub fn cancel_token(&self) -> CancelToken {
+        CancelToken::new(self.cancel_key, self.limits)
+    }
+
+    /// Sets the time limit of the following query, execute and transaction commands,
+    /// `None` for the server default. The server may lower it to its own limit,
+    /// which also applies to the other commands running SQL.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout.map(|timeout| timeout.as_millis() as u64);
+    }
+
+    /// Transaction state of the session as of the last response
+    pub fn transaction_state(&self) -> TransactionState {
+        self.transaction_state
+    }
+
+    async fn status(reader: &mut BufStream<T>, limits: &DecodeLimits) -> Result<()> {
+        read_status(reader, limits).await?.to_result()?;
         Ok(())
     }
 
-    pub async fn ping(&mut self) -> Result<()> {
-        write_command(&mut self.stream, Command::Ping).await?;
-        Self::status(&mut self.stream).await?;
+    async fn response(&mut self) -> Result<()> {
+        let (status, state) = read_response(&mut self.stream, &self.limits).await?;
+        self.transaction_state = state;
+        status.to_result()?;
+        Ok(())
+    }
+
+    async fn send(&mut self, cmd: Command) -> Result<()> {
+        if self.broken {
+            return Err(Error::Broken);
+        }
+        // Skip the remaining batches of an unfinished query stream
+        while let Some(columns) = self.unfinished {
+            if !matches!(
+                read_batch(&mut self.stream, columns, &self.limits).await?,
+                Batch::Rows(_)
+            ) {
+                self.unfinished = None;
+            }
+        }
+        // Roll back a dropped transaction, which may be over already if it failed
+        if self.rollback {
+            self.rollback = false;
+            write_command(&mut self.stream, Command::Rollback).await?;
+            if let Err(Error::Protocol(e)) = self.response().await {
+                return Err(e.into());
+            }
+        }
+        write_command(&mut self.stream, cmd).await?;
         Ok(())
     }
 
-    pub async fn execute<S: Into<String>>(&mut self, sql: S) -> Result<()> {
-        write_command(&mut self.stream, Command::SimpleExecute { sql: sql.into() }).await?;
-        Self::status(&mut self.stream).await?;
+    async fn read_query(&mut self) -> Result<Query> {
+        let columns = read_columns(&mut self.stream, &self.limits).await?;
+        let max = self.limits.max_values;
+        let mut values = Vec::new();
+        loop {
+            match read_batch(&mut self.stream, columns.len(), &self.limits).await? {
+                Batch::Rows(batch) => {
+                    // Bound the whole result, not only each batch
+                    let total = (values.len() + batch.len()) as u64;
+                    if total > max {
+                        return Err(ProtocolError::TooLarge("Result values", total, max).into());
+                    }
+                    values.extend(batch);
+                }
+                Batch::Done { changes, duration } => {
+                    return Ok(Query {
+                        columns,
+                        values,
+                        rows_affected: changes.rows_affected,
+                        total_changes: changes.total_changes,
+                        last_insert_rowid: changes.last_insert_rowid,
+                        duration,
+                    });
+                }
+                Batch::Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.send(Command::Ping).await?;
+        self.response().await?;
         Ok(())
     }
 
+    pub async fn execute<S: Into<String>>(&mut self, sql: S) -> Result<Changes> {
+        self.execute_with(sql, Parameters::default()).await
+    }
+
+    /// Executes a single statement with bound parameters, e.g. `[1, 2]` for `?1, ?2`
+    /// or `[(":name", "Dog")]` for `:name`
+    pub async fn execute_with<S: Into<String>, P: Into<Parameters>>(
+        &mut self,
+        sql: S,
+        params: P,
+    ) -> Result<Changes> {
+        let cmd = Command::SimpleExecute {
+            sql: sql.into(),
+            params: params.into(),
+            timeout: self.timeout,
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let changes = read_changes(&mut self.stream).await?;
+        Ok(changes)
+    }
+
     pub async fn query<S: Into<String>>(&mut self, sql: S) -> Result<Query> {
-        write_command(&mut self.stream, Command::SimpleQuery { sql: sql.into() }).await?;
-        Self::status(&mut self.stream).await?;
-        let query = read_query(&mut self.stream).await?;
-        Ok(query)
+        self.query_with(sql, Parameters::default()).await
     }
 
+    /// Queries a single statement with bound parameters, see [`Connection::execute_with`]
+    pub async fn query_with<S: Into<String>, P: Into<Parameters>>(
+        &mut self,
+        sql: S,
+        params: P,
+    ) -> Result<Query> {
+        let cmd = Command::SimpleQuery {
+            sql: sql.into(),
+            params: params.into(),
+            timeout: self.timeout,
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        self.read_query().await
+    }
+
+    /// Queries with bound parameters like [`Connection::query_with`],
+    /// but yields the rows as they arrive instead of collecting them
+    pub async fn query_stream<S: Into<String>, P: Into<Parameters>>(
+        &mut self,
+        sql: S,
+        params: P,
+    ) -> Result<QueryStream<'_, T>>
+    where
+        T: Send,
+    {
+        let cmd = Command::SimpleQuery {
+            sql: sql.into(),
+            params: params.into(),
+            timeout: self.timeout,
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let columns = read_columns(&mut self.stream, &self.limits).await?;
+        Ok(QueryStream::new(self, columns))
+    }
+
+    /// Runs the statements in a transaction, returning the changes of each statement
     pub async fn transaction<I: IntoIterator<Item = S>, S: ToString>(
         &mut self,
         sqls: I,
-    ) -> Result<()> {
+    ) -> Result<Vec<Changes>> {
         let sqls = sqls.into_iter().map(|s| s.to_string()).collect::<Vec<_>>();
-        write_command(&mut self.stream, Command::Transaction { sqls }).await?;
-        Self::status(&mut self.stream).await?;
+        let cmd = Command::Transaction {
+            sqls,
+            timeout: self.timeout,
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let changes = read_transaction(&mut self.stream, &self.limits).await?;
+        Ok(changes)
+    }
+
+    /// Begins a transaction, rolled back if dropped without being committed
+    pub async fn begin(&mut self, mode: TransactionMode) -> Result<Transaction<'_, T>> {
+        self.send(Command::Begin { mode }).await?;
+        self.response().await?;
+        Ok(Transaction::new(self))
+    }
+
+    /// Prepares a statement on the server, which limits how many a session may hold,
+    /// close the statements no longer needed
+    pub async fn prepare<S: Into<String>>(&mut self, sql: S) -> Result<Statement> {
+        self.send(Command::Prepare { sql: sql.into() }).await?;
+        self.response().await?;
+        let prepared = read_prepared(&mut self.stream, &self.limits).await?;
+        Ok(Statement {
+            id: prepared.stmt,
+            parameters: prepared.parameters,
+        })
+    }
+
+    /// Runs every statement of a script, returning the result of each statement
+    /// in order, up to and including the statement that failed if any.
+    /// The statement taking the rows of the script over the server's limit fails.
+    pub async fn script<S: Into<String>>(&mut self, sql: S) -> Result<Script> {
+        self.send(Command::Script { sql: sql.into() }).await?;
+        self.response().await?;
+        let script = read_script(&mut self.stream, &self.limits).await?;
+        Ok(script)
+    }
+
+    /// Opens a server-side cursor to fetch the rows of a query on demand
+    pub async fn open_cursor<S: Into<String>, P: Into<Parameters>>(
+        &mut self,
+        sql: S,
+        params: P,
+    ) -> Result<Cursor> {
+        let cmd = Command::OpenCursor {
+            sql: sql.into(),
+            params: params.into(),
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let cursor = read_cursor(&mut self.stream, &self.limits).await?;
+        Ok(Cursor {
+            id: cursor.cursor,
+            columns: cursor.columns,
+        })
+    }
+
+    /// Value of `limit` in effect on the session
+    pub async fn limit(&mut self, limit: Limit) -> Result<u64> {
+        self.send(Command::SetLimit { limit, value: None }).await?;
+        self.response().await?;
+        let value = read_limit(&mut self.stream).await?;
+        Ok(value)
+    }
+
+    /// Sets `limit` to `value`, returning the value in effect,
+    /// which the server or SQLite may have lowered
+    pub async fn set_limit(&mut self, limit: Limit, value: u64) -> Result<u64> {
+        let cmd = Command::SetLimit {
+            limit,
+            value: Some(value),
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let value = read_limit(&mut self.stream).await?;
+        Ok(value)
+    }
+
+    /// Whether `option` is on for the session
+    pub async fn db_config(&mut self, option: DbConfig) -> Result<bool> {
+        let cmd = Command::SetDbConfig {
+            option,
+            enabled: None,
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let enabled = read_db_config(&mut self.stream).await?;
+        Ok(enabled)
+    }
+
+    /// Turns `option` on or off, returning whether it is on.
+    /// Fails if the server pinned the option the other way
+    pub async fn set_db_config(&mut self, option: DbConfig, enabled: bool) -> Result<bool> {
+        let cmd = Command::SetDbConfig {
+            option,
+            enabled: Some(enabled),
+        };
+        self.send(cmd).await?;
+        self.response().await?;
+        let enabled = read_db_config(&mut self.stream).await?;
+        Ok(enabled)
+    }
+
+    /// Loads an extension the server allows by `name`, calling `entry_point` if given
+    /// or the one SQLite derives from the file name of the extension
+    pub async fn load_extension<S: Into<String>>(
+        &mut self,
+        name: S,
+        entry_point: Option<&str>,
+    ) -> Result<()> {
+        let cmd = Command::LoadExtension {
+            name: name.into(),
+            entry_point: entry_point.map(String::from),
+        };
+        self.send(cmd).await?;
+        self.response().await?;
         Ok(())
     }
 
     pub async fn disconnect(&mut self) -> Result<()> {
-        write_command(&mut self.stream, Command::Disconnect).await?;
+        self.send(Command::Disconnect).await?;
+        Ok(())
+    }
+}
+
+/// A statement prepared on the server, valid until closed or the connection is dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    id: u64,
+    parameters: Vec<Option<String>>,
+}
+
+impl Statement {
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.len()
+    }
+
+    /// Parameter names in index order, `None` for anonymous parameters (`?`)
+    pub fn parameter_names(&self) -> &[Option<String>] {
+        &self.parameters
+    }
+
+    pub async fn execute<T, P>(&self, conn: &mut Connection<T>, params: P) -> Result<Changes>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+        P: Into<Parameters>,
+    {
+        let cmd = Command::ExecutePrepared {
+            stmt: self.id,
+            params: params.into(),
+        };
+        conn.send(cmd).await?;
+        conn.response().await?;
+        let changes = read_changes(&mut conn.stream).await?;
+        Ok(changes)
+    }
+
+    pub async fn query<T, P>(&self, conn: &mut Connection<T>, params: P) -> Result<Query>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+        P: Into<Parameters>,
+    {
+        let cmd = Command::QueryPrepared {
+            stmt: self.id,
+            params: params.into(),
+        };
+        conn.send(cmd).await?;
+        conn.response().await?;
+        conn.read_query().await
+    }
+
+    pub async fn close<T>(self, conn: &mut Connection<T>) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        conn.send(Command::ClosePrepared { stmt: self.id }).await?;
+        conn.response().await?;
+        Ok(())
+    }
+}
+
+/// A cursor opened on the server, valid until closed or the connection is dropped
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    id: u64,
+    columns: Vec<Column>,
+}
+
+impl Cursor {
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Fetches up to `max_rows` rows, the server may return fewer
+    /// rows than asked for even if the cursor is not done yet
+    pub async fn fetch<T>(&self, conn: &mut Connection<T>, max_rows: u64) -> Result<Fetched>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let cmd = Command::Fetch {
+            cursor: self.id,
+            max_rows,
+        };
+        conn.send(cmd).await?;
+        conn.response().await?;
+        let fetched = read_fetched(&mut conn.stream, self.columns.len(), &conn.limits).await?;
+        Ok(fetched)
+    }
+
+    pub async fn close<T>(self, conn: &mut Connection<T>) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        conn.send(Command::CloseCursor { cursor: self.id }).await?;
+        conn.response().await?;
         Ok(())
     }
 }
@@ -0,0 +1,91 @@
+use crate::{Error, Result};
+use protocol::{DecodeLimits, Params};
+
+/// Settings of [`crate::Connection::connect_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectOptions {
+    /// The least Argon2 costs the server may ask the password to be hashed with,
+    /// to refuse servers downgrading the hash. Each cost is bounded on its own
+    pub min_params: Params,
+    /// Whether to also refuse costs meeting none of the [`protocol::OWASP_MINIMUMS`],
+    /// which trade memory cost for iterations, so `min_params` alone can't express them
+    pub require_owasp: bool,
+    /// The most Argon2 costs the server may ask the password to be hashed with,
+    /// to refuse servers asking for more memory or time than the client can spare
+    pub max_params: Params,
+    /// Whether to refuse servers that don't prove they know the password too:
+    /// servers older than protocol version 2.1, and servers trusting a client certificate
+    /// instead of asking for the password, which only TLS then authenticates
+    pub require_server_proof: bool,
+    /// Bounds on the lengths and counts the server's responses may announce
+    pub decode_limits: DecodeLimits,
+}
+
+impl Default for ConnectOptions {
+    /// Allows the costs recommended by OWASP up to 256 MiB, 16 iterations and 8 lanes,
+    /// requires the server proof and has the default decode limits
+    fn default() -> Self {
+        ConnectOptions {
+            min_params: Params {
+                m_cost: 7168,
+                t_cost: 1,
+                p_cost: 1,
+            },
+            require_owasp: true,
+            max_params: Params {
+                m_cost: 262144,
+                t_cost: 16,
+                p_cost: 8,
+            },
+            require_server_proof: true,
+            decode_limits: DecodeLimits::DEFAULT,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Fails unless each cost of `params` is within the bounds,
+    /// and unless they meet the OWASP minimums if required
+    pub(crate) fn check(&self, params: Params) -> Result<()> {
+        let (min, max) = (self.min_params, self.max_params);
+        let within = (min.m_cost..=max.m_cost).contains(&params.m_cost)
+            && (min.t_cost..=max.t_cost).contains(&params.t_cost)
+            && (min.p_cost..=max.p_cost).contains(&params.p_cost)
+            && (!self.require_owasp || params.meets_owasp());
+        match within {
+            true => Ok(()),
+            false => Err(Error::HashParams(params)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(m_cost: u32, t_cost: u32) -> Params {
+        Params {
+            m_cost,
+            t_cost,
+            p_cost: 1,
+        }
+    }
+
+    #[test]
+    fn default_owasp() {
+        let options = ConnectOptions::default();
+        for (m_cost, t_cost) in protocol::OWASP_MINIMUMS {
+            assert!(options.check(params(m_cost, t_cost)).is_ok());
+        }
+        assert!(options.check(Params::default()).is_ok());
+        // Each cost is within its own bounds, but not the pair
+        assert!(options.check(params(7168, 1)).is_err());
+        assert!(options.check(params(19455, 2)).is_err());
+        let options = ConnectOptions {
+            require_owasp: false,
+            ..ConnectOptions::default()
+        };
+        assert!(options.check(params(7168, 1)).is_ok());
+        assert!(options.check(params(7167, 5)).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::{Connection, Result};
+use futures_core::Stream;
+use protocol::{Batch, Changes, Column, Value, read_batch};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+type ReadBatch<'a, T> = Pin<
+    Box<dyn Future<Output = (&'a mut Connection<T>, Result<Batch, protocol::Error>)> + Send + 'a>,
+>;
+
+enum State<'a, T> {
+    Idle(&'a mut Connection<T>),
+    Reading(ReadBatch<'a, T>),
+    Done,
+}
+
+/// Rows of a query, read from the server one batch at a time
+///
+/// Dropping the stream before its end is fine, the remaining
+/// batches are skipped before the next command is sent.
+///
+/// Dropping it while a batch is part-read is not, such as when `tokio::time::timeout`
+/// or `select!` gives up on the stream: the rest of the batch can't be told apart
+/// from the following responses, so the connection is broken and its later commands
+/// fail with [`Error::Broken`](crate::Error::Broken). Cancelling a `next()` call
+/// and polling the stream again later is fine, the read resumes where it stopped.
+pub struct QueryStream<'a, T> {
+    columns: Vec<Column>,
+    batch: std::vec::IntoIter<Value>,
+    state: State<'a, T>,
+    changes: Option<Changes>,
+    duration: Option<u64>,
+}
+
+impl<'a, T> QueryStream<'a, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub(crate) fn new(conn: &'a mut Connection<T>, columns: Vec<Column>) -> Self {
+        conn.unfinished = Some(columns.len());
+        Self {
+            columns,
+            batch: Vec::new().into_iter(),
+            state: State::Idle(conn),
+            changes: None,
+            duration: None,
+        }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Available once the stream has ended
+    pub fn changes(&self) -> Option<Changes> {
+        self.changes
+    }
+
+    /// Available once the stream has ended
+    pub fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+}
+
+impl<T> Stream for QueryStream<'_, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Item = Result<Vec<Value>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let width = this.columns.len();
+        loop {
+            if width > 0 && this.batch.len() >= width {
+                let row = this.batch.by_ref().take(width).collect();
+                return Poll::Ready(Some(Ok(row)));
+            }
+            match std::mem::replace(&mut this.state, State::Done) {
+                State::Idle(conn) => {
+                    this.state = State::Reading(Box::pin(async move {
+                        // Left set if the read is dropped part-way
+                        conn.broken = true;
+                        let batch = read_batch(&mut conn.stream, width, &conn.limits).await;
+                        conn.broken = false;
+                        (conn, batch)
+                    }));
+                }
+                State::Reading(mut read) => match read.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        this.state = State::Reading(read);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((conn, batch)) => {
+                        let batch = match batch {
+                            Ok(batch) => batch,
+                            Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                        };
+                        match batch {
+                            Batch::Rows(values) => {
+                                this.batch = values.into_iter();
+                                this.state = State::Idle(conn);
+                            }
+                            Batch::Done { changes, duration } => {
+                                conn.unfinished = None;
+                                this.changes = Some(changes);
+                                this.duration = Some(duration);
+                                return Poll::Ready(None);
+                            }
+                            Batch::Err(err) => {
+                                conn.unfinished = None;
+                                return Poll::Ready(Some(Err(err.into())));
+                            }
+                        }
+                    }
+                },
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{Error, Result};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+/// TLS settings to wrap a stream in before [`crate::Connection::connect`],
+/// see `examples/tls.rs`
+#[derive(Clone)]
+pub struct Tls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl Tls {
+    /// Trusts the certificates of the PEM `ca_file` only, which may be a self-signed
+    /// server certificate, and expects the server certificate to be valid for `server_name`
+    pub fn new<P: AsRef<Path>, S: Into<String>>(ca_file: P, server_name: S) -> Result<Self> {
+        Self::build(ca_file.as_ref(), server_name.into(), None)
+    }
+
+    /// Like [`Tls::new`], also presenting the PEM client certificate chain `cert_file`
+    /// and its PEM private key `key_file` to servers requiring client certificates
+    pub fn with_client_cert<P, S, C, K>(
+        ca_file: P,
+        server_name: S,
+        cert_file: C,
+        key_file: K,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        S: Into<String>,
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let certs = CertificateDer::pem_file_iter(cert_file)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(key_file)?;
+        Self::build(ca_file.as_ref(), server_name.into(), Some((certs, key)))
+    }
+
+    fn build(
+        ca_file: &Path,
+        server_name: String,
+        client_cert: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca_file)? {
+            roots.add(cert?)?;
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((certs, key)) => builder.with_client_auth_cert(certs, key)?,
+            None => builder.with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|e| Error::InvalidServerName(e.to_string()))?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect<T>(&self, stream: T) -> Result<TlsStream<T>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await?;
+        Ok(stream)
+    }
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
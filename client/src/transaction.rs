@@ -0,0 +1,85 @@
+use crate::{Connection, Result};
+use protocol::Command;
+use std::ops::{Deref, DerefMut};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A transaction begun with [`Connection::begin`], usable as the connection itself
+///
+/// Dropping the transaction without committing it rolls it back
+/// before the next command is sent.
+#[derive(Debug)]
+pub struct Transaction<'a, T> {
+    conn: &'a mut Connection<T>,
+    finished: bool,
+}
+
+impl<'a, T> Transaction<'a, T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(crate) fn new(conn: &'a mut Connection<T>) -> Self {
+        Self {
+            conn,
+            finished: false,
+        }
+    }
+
+    pub async fn commit(mut self) -> Result<()> {
+        self.conn.send(Command::Commit).await?;
+        self.conn.response().await?;
+        self.finished = true;
+        Ok(())
+    }
+
+    pub async fn rollback(mut self) -> Result<()> {
+        self.conn.send(Command::Rollback).await?;
+        self.finished = true;
+        self.conn.response().await?;
+        Ok(())
+    }
+
+    pub async fn savepoint<S: Into<String>>(&mut self, name: S) -> Result<()> {
+        let cmd = Command::Savepoint { name: name.into() };
+        self.conn.send(cmd).await?;
+        self.conn.response().await?;
+        Ok(())
+    }
+
+    /// Releases a savepoint and every savepoint begun after it
+    pub async fn release<S: Into<String>>(&mut self, name: S) -> Result<()> {
+        let cmd = Command::Release { name: name.into() };
+        self.conn.send(cmd).await?;
+        self.conn.response().await?;
+        Ok(())
+    }
+
+    /// Rolls back the changes made since a savepoint, which stays active
+    pub async fn rollback_to<S: Into<String>>(&mut self, name: S) -> Result<()> {
+        let cmd = Command::RollbackTo { name: name.into() };
+        self.conn.send(cmd).await?;
+        self.conn.response().await?;
+        Ok(())
+    }
+}
+
+impl<T> Deref for Transaction<'_, T> {
+    type Target = Connection<T>;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+    }
+}
+
+impl<T> DerefMut for Transaction<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn
+    }
+}
+
+impl<T> Drop for Transaction<'_, T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.conn.rollback = true;
+        }
+    }
+}
@@ -0,0 +1,31 @@
+/// Bounds on the lengths and counts read from the wire,
+/// so that a peer can't have huge buffers allocated by announcing them.
+/// Each reader of a message that may be large is given the limits to apply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Bytes of a string or BLOB
+    pub max_string_length: u64,
+    /// Bytes of the SQL of a command
+    pub max_sql_length: u64,
+    /// Statements of a transaction or script
+    pub max_statements: u64,
+    /// Values of a result, all its batches together unless streamed, of all the statements
+    /// of a script together or of a parameter list, and columns of a result
+    pub max_values: u64,
+}
+
+impl DecodeLimits {
+    /// The default limits, the SQLite defaults where SQLite has one
+    pub const DEFAULT: DecodeLimits = DecodeLimits {
+        max_string_length: 1_000_000_000,
+        max_sql_length: 1_000_000_000,
+        max_statements: 100_000,
+        max_values: 100_000_000,
+    };
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -1,6 +1,16 @@
-use crate::{Error, Result};
+use crate::{DecodeLimits, Error, Result};
+use std::io::ErrorKind;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// Bytes or elements allocated up front for a length read from the wire,
+/// the rest being allocated as the data actually arrives
+const PREALLOCATE: usize = 8 * 1024;
+
+/// Capacity to allocate for `len` elements read from the wire
+pub fn capacity(len: usize) -> usize {
+    len.min(PREALLOCATE)
+}
+
 pub trait WriteExt: AsyncWrite + Unpin {
     /// Writes a length as a variable-length integer (varint)
     async fn write_len(&mut self, mut len: u64) -> Result<()> {
@@ -50,15 +60,37 @@ pub trait ReadExt: AsyncRead + Unpin {
         Ok(len)
     }
 
-    async fn read_bytes(&mut self) -> Result<Vec<u8>> {
-        let len = self.read_len().await? as usize;
-        let mut buf = vec![0; len];
-        self.read_exact(&mut buf).await?;
+    /// Reads a count of elements, failing if it is more than `max`
+    async fn read_count(&mut self, what: &'static str, max: u64) -> Result<usize> {
+        let len = self.read_len().await?;
+        if len > max {
+            return Err(Error::TooLarge(what, len, max));
+        }
+        Ok(len as usize)
+    }
+
+    async fn read_bytes_max(&mut self, what: &'static str, max: u64) -> Result<Vec<u8>> {
+        let len = self.read_count(what, max).await?;
+        let mut buf = Vec::with_capacity(capacity(len));
+        (&mut *self).take(len as u64).read_to_end(&mut buf).await?;
+        if buf.len() < len {
+            return Err(Error::IoError(ErrorKind::UnexpectedEof.into()));
+        }
         Ok(buf)
     }
 
-    async fn read_string(&mut self) -> Result<String> {
-        let buf = self.read_bytes().await?;
+    async fn read_bytes(&mut self, limits: &DecodeLimits) -> Result<Vec<u8>> {
+        self.read_bytes_max("String", limits.max_string_length)
+            .await
+    }
+
+    async fn read_string(&mut self, limits: &DecodeLimits) -> Result<String> {
+        let buf = self.read_bytes(limits).await?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    async fn read_sql(&mut self, limits: &DecodeLimits) -> Result<String> {
+        let buf = self.read_bytes_max("SQL", limits.max_sql_length).await?;
         Ok(String::from_utf8(buf)?)
     }
 }
@@ -66,6 +98,67 @@ pub trait ReadExt: AsyncRead + Unpin {
 impl<W: AsyncWrite + Unpin> WriteExt for W {}
 impl<R: AsyncRead + Unpin> ReadExt for R {}
 
-// TODO
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn len_round_trip() {
+        for len in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            buf.write_len(len).await.unwrap();
+            assert_eq!(buf.as_slice().read_len().await.unwrap(), len);
+        }
+    }
+
+    #[tokio::test]
+    async fn len_overflow() {
+        let buf = [0xFF; 11];
+        assert!(matches!(
+            buf.as_slice().read_len().await,
+            Err(Error::Varint)
+        ));
+    }
+
+    #[tokio::test]
+    async fn count_limit() {
+        let mut buf = Vec::new();
+        buf.write_len(10).await.unwrap();
+        assert_eq!(buf.as_slice().read_count("Values", 10).await.unwrap(), 10);
+        let count = buf.as_slice().read_count("Values", 9).await;
+        assert!(matches!(count, Err(Error::TooLarge("Values", 10, 9))));
+    }
+
+    #[tokio::test]
+    async fn bytes_limit() {
+        let mut buf = Vec::new();
+        buf.write_bytes(b"echolite").await.unwrap();
+        let bytes = buf.as_slice().read_bytes_max("String", 8).await.unwrap();
+        assert_eq!(bytes, b"echolite");
+        let bytes = buf.as_slice().read_bytes_max("String", 7).await;
+        assert!(matches!(bytes, Err(Error::TooLarge("String", 8, 7))));
+    }
+
+    #[tokio::test]
+    async fn bytes_truncated() {
+        let mut buf = Vec::new();
+        buf.write_bytes(b"echolite").await.unwrap();
+        buf.truncate(4);
+        let bytes = buf.as_slice().read_bytes_max("String", 8).await;
+        assert!(matches!(bytes, Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn string_and_sql_limits() {
+        let limits = DecodeLimits {
+            max_string_length: 4,
+            max_sql_length: 8,
+            ..DecodeLimits::DEFAULT
+        };
+        let mut buf = Vec::new();
+        buf.write_string("SELECT 1").await.unwrap();
+        assert_eq!(buf.as_slice().read_sql(&limits).await.unwrap(), "SELECT 1");
+        let string = buf.as_slice().read_string(&limits).await;
+        assert!(matches!(string, Err(Error::TooLarge("String", 8, 4))));
+    }
+}
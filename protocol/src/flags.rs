@@ -1,4 +1,5 @@
 /// From: https://www.sqlite.org/c3ref/c_open_autoproxy.html
+/// and https://www.sqlite.org/rescode.html
 pub mod consts {
     pub const SQLITE_OPEN_READONLY: i32 = 0x00000001;
     pub const SQLITE_OPEN_READWRITE: i32 = 0x00000002;
@@ -22,6 +23,46 @@ pub mod consts {
     pub const SQLITE_OPEN_WAL: i32 = 0x00080000;
     pub const SQLITE_OPEN_NOFOLLOW: i32 = 0x01000000;
     pub const SQLITE_OPEN_EXRESCODE: i32 = 0x02000000;
+
+    pub const SQLITE_ERROR: i32 = 1;
+    pub const SQLITE_INTERNAL: i32 = 2;
+    pub const SQLITE_PERM: i32 = 3;
+    pub const SQLITE_ABORT: i32 = 4;
+    pub const SQLITE_BUSY: i32 = 5;
+    pub const SQLITE_LOCKED: i32 = 6;
+    pub const SQLITE_NOMEM: i32 = 7;
+    pub const SQLITE_READONLY: i32 = 8;
+    pub const SQLITE_INTERRUPT: i32 = 9;
+    pub const SQLITE_IOERR: i32 = 10;
+    pub const SQLITE_CORRUPT: i32 = 11;
+    pub const SQLITE_NOTFOUND: i32 = 12;
+    pub const SQLITE_FULL: i32 = 13;
+    pub const SQLITE_CANTOPEN: i32 = 14;
+    pub const SQLITE_PROTOCOL: i32 = 15;
+    pub const SQLITE_EMPTY: i32 = 16;
+    pub const SQLITE_SCHEMA: i32 = 17;
+    pub const SQLITE_TOOBIG: i32 = 18;
+    pub const SQLITE_CONSTRAINT: i32 = 19;
+    pub const SQLITE_MISMATCH: i32 = 20;
+    pub const SQLITE_MISUSE: i32 = 21;
+    pub const SQLITE_NOLFS: i32 = 22;
+    pub const SQLITE_AUTH: i32 = 23;
+    pub const SQLITE_FORMAT: i32 = 24;
+    pub const SQLITE_RANGE: i32 = 25;
+    pub const SQLITE_NOTADB: i32 = 26;
+
+    pub const SQLITE_CONSTRAINT_CHECK: i32 = SQLITE_CONSTRAINT | (1 << 8);
+    pub const SQLITE_CONSTRAINT_COMMITHOOK: i32 = SQLITE_CONSTRAINT | (2 << 8);
+    pub const SQLITE_CONSTRAINT_FOREIGNKEY: i32 = SQLITE_CONSTRAINT | (3 << 8);
+    pub const SQLITE_CONSTRAINT_FUNCTION: i32 = SQLITE_CONSTRAINT | (4 << 8);
+    pub const SQLITE_CONSTRAINT_NOTNULL: i32 = SQLITE_CONSTRAINT | (5 << 8);
+    pub const SQLITE_CONSTRAINT_PRIMARYKEY: i32 = SQLITE_CONSTRAINT | (6 << 8);
+    pub const SQLITE_CONSTRAINT_TRIGGER: i32 = SQLITE_CONSTRAINT | (7 << 8);
+    pub const SQLITE_CONSTRAINT_UNIQUE: i32 = SQLITE_CONSTRAINT | (8 << 8);
+    pub const SQLITE_CONSTRAINT_VTAB: i32 = SQLITE_CONSTRAINT | (9 << 8);
+    pub const SQLITE_CONSTRAINT_ROWID: i32 = SQLITE_CONSTRAINT | (10 << 8);
+    pub const SQLITE_CONSTRAINT_PINNED: i32 = SQLITE_CONSTRAINT | (11 << 8);
+    pub const SQLITE_CONSTRAINT_DATATYPE: i32 = SQLITE_CONSTRAINT | (12 << 8);
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
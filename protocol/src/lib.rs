@@ -1,11 +1,13 @@
+mod decode;
 mod ext;
 mod flags;
 
 use argon2::{Algorithm, Argon2, Params as Argon2Params, Version as Argon2Version};
-use ext::{ReadExt, WriteExt};
+use ext::{ReadExt, WriteExt, capacity};
 use rand::Rng;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub use decode::*;
 pub use flags::*;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
@@ -25,12 +27,36 @@ pub enum Error {
     Argon2Params,
     #[error("Argon2 Error: {0}")]
     Argon2(argon2::Error),
+    #[error("Unknown Request: {0}")]
+    UnknownRequest(u8),
+    #[error("Unknown Auth Method: {0}")]
+    UnknownAuthMethod(u8),
+    #[error("Unknown Prehash: {0}")]
+    UnknownPrehash(u8),
     #[error("Unknown Status: {0}")]
     UnknownStatus(u8),
+    #[error("Unknown Error Class: {0}")]
+    UnknownErrorClass(u8),
     #[error("Unknown Command: {0}")]
     UnknownCommand(u8),
     #[error("Unknown Value: {0}")]
     UnknownValue(u8),
+    #[error("Unknown Parameters: {0}")]
+    UnknownParameters(u8),
+    #[error("Unknown Batch: {0}")]
+    UnknownBatch(u8),
+    #[error("Unknown Statement Result: {0}")]
+    UnknownStatementResult(u8),
+    #[error("Unknown Transaction Mode: {0}")]
+    UnknownTransactionMode(u8),
+    #[error("Unknown Limit: {0}")]
+    UnknownLimit(u8),
+    #[error("Unknown Database Option: {0}")]
+    UnknownDbConfig(u8),
+    #[error("Unknown Transaction State: {0}")]
+    UnknownTransactionState(u8),
+    #[error("Too Large: {0} of length {1}, at most {2}")]
+    TooLarge(&'static str, u64, u64),
     #[error("Invalid query values length: values {0}, columns {1}")]
     InvalidValuesLength(usize, usize),
 }
@@ -41,10 +67,14 @@ pub struct Version {
     pub minor: u8,
 }
 
+pub const VERSION: Version = Version { major: 2, minor: 1 };
+
+/// The first version answering [`Request::MutualSession`]
+pub const MUTUAL_VERSION: Version = Version { major: 2, minor: 1 };
+
 pub async fn write_protocol_version<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
-    let version = Version { major: 1, minor: 0 };
-    writer.write_u8(version.major).await?;
-    writer.write_u8(version.minor).await?;
+    writer.write_u8(VERSION.major).await?;
+    writer.write_u8(VERSION.minor).await?;
     writer.flush().await?;
     Ok(())
 }
@@ -55,8 +85,80 @@ pub async fn read_protocol_version<R: AsyncRead + Unpin>(reader: &mut R) -> Resu
     Ok(Version { major, minor })
 }
 
+/// What a new connection is for, sent by the client after reading the protocol version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Authenticate and open a session on a database
+    Session,
+    /// Like [`Request::Session`], the server then also proving it knows the password,
+    /// see [`write_mutual_auth_password`]. Servers answer it since [`MUTUAL_VERSION`]
+    MutualSession,
+    /// Interrupt whatever the session holding the cancel key is running, then disconnect
+    Cancel(CancelKey),
+}
+
+pub async fn write_request<W: AsyncWrite + Unpin>(writer: &mut W, request: Request) -> Result<()> {
+    match request {
+        Request::Session => {
+            writer.write_u8(0).await?;
+        }
+        Request::Cancel(key) => {
+            writer.write_u8(1).await?;
+            writer.write_all(&key).await?;
+        }
+        Request::MutualSession => {
+            writer.write_u8(2).await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Request> {
+    match reader.read_u8().await? {
+        0 => Ok(Request::Session),
+        1 => Ok(Request::Cancel(read_cancel_key(reader).await?)),
+        2 => Ok(Request::MutualSession),
+        n => Err(Error::UnknownRequest(n)),
+    }
+}
+
+/// How a session is authenticated, sent by the server in reply to [`Request::Session`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// The client proves the password, see [`write_auth_password`]
+    Password,
+    /// The client already presented a trusted TLS certificate, no password step follows
+    Certificate,
+}
+
+pub async fn write_auth_method<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    method: AuthMethod,
+) -> Result<()> {
+    let tag = match method {
+        AuthMethod::Password => 0,
+        AuthMethod::Certificate => 1,
+    };
+    writer.write_u8(tag).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_auth_method<R: AsyncRead + Unpin>(reader: &mut R) -> Result<AuthMethod> {
+    match reader.read_u8().await? {
+        0 => Ok(AuthMethod::Password),
+        1 => Ok(AuthMethod::Certificate),
+        n => Err(Error::UnknownAuthMethod(n)),
+    }
+}
+
 pub type Salt = [u8; 16];
 pub type HashedPassword = [u8; 32];
+/// Sent by the server once it verified the password in a [`Request::MutualSession`]
+pub type ServerProof = [u8; 32];
+/// Secret issued with each session, allowing another connection to cancel its commands
+pub type CancelKey = [u8; 32];
 
 pub async fn write_salt<W: AsyncWrite + Unpin>(writer: &mut W, salt: Salt) -> Result<()> {
     writer.write_all(&salt).await?;
@@ -70,6 +172,24 @@ pub async fn read_salt<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Salt> {
     Ok(buf)
 }
 
+/// Longest user name in bytes, read before the user is authenticated
+pub const MAX_USER_LENGTH: u64 = 256;
+
+/// Name of the user to authenticate as, empty when the server has a single password
+pub async fn write_user<W: AsyncWrite + Unpin, U: AsRef<str>>(
+    writer: &mut W,
+    user: U,
+) -> Result<()> {
+    writer.write_string(user).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_user<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let buf = reader.read_bytes_max("User", MAX_USER_LENGTH).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Params {
     pub m_cost: u32,
@@ -87,6 +207,20 @@ impl Default for Params {
     }
 }
 
+/// The OWASP recommended minimums of Argon2id, as pairs of memory cost in KiB
+/// and iterations, all with a degree of parallelism of 1
+pub const OWASP_MINIMUMS: [(u32, u32); 5] =
+    [(47104, 1), (19456, 2), (12288, 3), (9216, 4), (7168, 5)];
+
+impl Params {
+    /// Whether the memory cost and iterations meet one of the [`OWASP_MINIMUMS`]
+    pub fn meets_owasp(&self) -> bool {
+        OWASP_MINIMUMS
+            .iter()
+            .any(|&(m_cost, t_cost)| self.m_cost >= m_cost && self.t_cost >= t_cost)
+    }
+}
+
 pub async fn write_hash_params<W: AsyncWrite + Unpin>(
     writer: &mut W,
     params: Params,
@@ -112,26 +246,101 @@ pub async fn read_hash_params<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Pa
     })
 }
 
-pub async fn to_hash_password<P: AsRef<str>>(
+/// Longest prehash salt in bytes, above any salt a PHC string can hold
+pub const MAX_SALT_LENGTH: u64 = 64;
+
+/// Argon2id salt and params the server stored a user's password hash with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prehash {
+    pub salt: Vec<u8>,
+    pub params: Params,
+}
+
+/// Sent after the hash params, `Some` when the client must answer with the
+/// [`to_prehash_password`] of the password in place of the password
+pub async fn write_prehash<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    prehash: Option<&Prehash>,
+) -> Result<()> {
+    match prehash {
+        Some(prehash) => {
+            writer.write_u8(1).await?;
+            writer.write_bytes(&prehash.salt).await?;
+            write_hash_params(writer, prehash.params).await?;
+        }
+        None => {
+            writer.write_u8(0).await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_prehash<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Prehash>> {
+    match reader.read_u8().await? {
+        0 => Ok(None),
+        1 => {
+            let salt = reader.read_bytes_max("Salt", MAX_SALT_LENGTH).await?;
+            let params = read_hash_params(reader).await?;
+            Ok(Some(Prehash { salt, params }))
+        }
+        n => Err(Error::UnknownPrehash(n)),
+    }
+}
+
+pub async fn to_hash_password<P: AsRef<[u8]>>(
+    password: P,
+    client_salt: Salt,
+    server_salt: Salt,
+    params: Params,
+) -> Result<HashedPassword> {
+    let mut salt = [0; 32];
+    salt[..16].copy_from_slice(&client_salt);
+    salt[16..].copy_from_slice(&server_salt);
+    argon2id(password.as_ref(), &salt, params).await
+}
+
+/// The answer of the client and the proof of the server in a [`Request::MutualSession`],
+/// the two halves of one hash so that neither can be derived from the other
+pub async fn to_mutual_hash_password<P: AsRef<[u8]>>(
     password: P,
     client_salt: Salt,
     server_salt: Salt,
     params: Params,
+) -> Result<(HashedPassword, ServerProof)> {
+    let mut salt = [0; 32];
+    salt[..16].copy_from_slice(&client_salt);
+    salt[16..].copy_from_slice(&server_salt);
+    let mut hash: [u8; 64] = argon2id(password.as_ref(), &salt, params).await?;
+    let mut answer = [0; 32];
+    let mut proof = [0; 32];
+    answer.copy_from_slice(&hash[..32]);
+    proof.copy_from_slice(&hash[32..]);
+    hash.zeroize();
+    Ok((answer, proof))
+}
+
+/// Hashes the password the way the server stored it, the result taking the place of the
+/// password in [`to_hash_password`]
+pub async fn to_prehash_password<P: AsRef<str>>(
+    password: P,
+    prehash: &Prehash,
 ) -> Result<HashedPassword> {
+    argon2id(password.as_ref().as_bytes(), &prehash.salt, prehash.params).await
+}
+
+async fn argon2id<const N: usize>(password: &[u8], salt: &[u8], params: Params) -> Result<[u8; N]> {
     #[derive(Zeroize, ZeroizeOnDrop)]
     struct Password(Vec<u8>);
 
-    let params = Argon2Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+    let params = Argon2Params::new(params.m_cost, params.t_cost, params.p_cost, Some(N))
         .map_err(Error::Argon2)?;
     let hasher = Argon2::new(Algorithm::Argon2id, Argon2Version::V0x13, params);
-    let password = Password(password.as_ref().as_bytes().to_vec());
-
-    let mut salt = [0; 32];
-    salt[..16].copy_from_slice(&client_salt);
-    salt[16..].copy_from_slice(&server_salt);
+    let password = Password(password.to_vec());
+    let salt = salt.to_vec();
 
     tokio::task::spawn_blocking(move || {
-        let mut out = [0; 32];
+        let mut out = [0; N];
         hasher
             .hash_password_into(&password.0, &salt, &mut out)
             .map_err(Error::Argon2)?;
@@ -146,7 +355,25 @@ pub fn rand_salt() -> Salt {
     salt
 }
 
-pub async fn write_auth_password<W: AsyncWrite + Unpin, P: AsRef<str>>(
+pub fn rand_cancel_key() -> CancelKey {
+    let mut key: CancelKey = [0; 32];
+    rand::rng().fill(&mut key);
+    key
+}
+
+pub async fn write_cancel_key<W: AsyncWrite + Unpin>(writer: &mut W, key: CancelKey) -> Result<()> {
+    writer.write_all(&key).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_cancel_key<R: AsyncRead + Unpin>(reader: &mut R) -> Result<CancelKey> {
+    let mut buf: CancelKey = [0; 32];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+pub async fn write_auth_password<W: AsyncWrite + Unpin, P: AsRef<[u8]>>(
     writer: &mut W,
     password: P,
     client_salt: Salt,
@@ -159,12 +386,45 @@ pub async fn write_auth_password<W: AsyncWrite + Unpin, P: AsRef<str>>(
     Ok(())
 }
 
+/// Like [`write_auth_password`] in a [`Request::MutualSession`],
+/// returning the proof the server has to answer with
+pub async fn write_mutual_auth_password<W: AsyncWrite + Unpin, P: AsRef<[u8]>>(
+    writer: &mut W,
+    password: P,
+    client_salt: Salt,
+    server_salt: Salt,
+    params: Params,
+) -> Result<ServerProof> {
+    let (p, proof) = to_mutual_hash_password(password, client_salt, server_salt, params).await?;
+    writer.write_all(&p).await?;
+    writer.flush().await?;
+    Ok(proof)
+}
+
+pub async fn write_server_proof<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    proof: ServerProof,
+) -> Result<()> {
+    writer.write_all(&proof).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_server_proof<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ServerProof> {
+    let mut buf = [0; 32];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
 pub async fn read_auth_password<R: AsyncRead + Unpin>(reader: &mut R) -> Result<HashedPassword> {
     let mut buf = [0; 32];
     reader.read_exact(&mut buf).await?;
     Ok(buf)
 }
 
+/// Longest database path in bytes
+pub const MAX_PATH_LENGTH: u64 = 4096;
+
 pub async fn write_connect<W: AsyncWrite + Unpin, P: AsRef<str>>(
     writer: &mut W,
     path: P,
@@ -177,20 +437,143 @@ pub async fn write_connect<W: AsyncWrite + Unpin, P: AsRef<str>>(
 }
 
 pub async fn read_connect<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(String, Flags)> {
-    let path = reader.read_string().await?;
+    let buf = reader.read_bytes_max("Path", MAX_PATH_LENGTH).await?;
+    let path = String::from_utf8(buf)?;
     let flags = reader.read_i32().await?;
     Ok((path, Flags::from_flags(flags)))
 }
 
+/// What an error response is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// Authentication failed
+    Auth,
+    /// The request could not be handled, e.g. an unknown statement handle
+    Protocol,
+    /// SQLite failed to run the request
+    Sqlite,
+    /// The request was refused by the server configuration
+    Policy,
+    /// The command was cancelled before it finished
+    Interrupted,
+    /// The command ran out of time and was interrupted
+    Timeout,
+}
+
+impl std::fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorClass::Auth => write!(f, "Auth"),
+            ErrorClass::Protocol => write!(f, "Protocol"),
+            ErrorClass::Sqlite => write!(f, "SQLite"),
+            ErrorClass::Policy => write!(f, "Policy"),
+            ErrorClass::Interrupted => write!(f, "Interrupted"),
+            ErrorClass::Timeout => write!(f, "Timeout"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorStatus {
+    pub class: ErrorClass,
+    /// Primary SQLite result code, e.g. `SQLITE_CONSTRAINT`, 0 if the error is not from SQLite
+    pub code: i32,
+    /// Extended SQLite result code, e.g. `SQLITE_CONSTRAINT_UNIQUE`, 0 if the error is not from SQLite
+    pub extended_code: i32,
+    /// Byte offset of the error in the SQL, if SQLite provides it
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl ErrorStatus {
+    /// An error that doesn't come from SQLite
+    pub fn new<S: Into<String>>(class: ErrorClass, message: S) -> Self {
+        Self {
+            class,
+            code: 0,
+            extended_code: 0,
+            offset: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if self.code != 0 {
+            write!(f, " (code {})", self.extended_code)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset}")?;
+        }
+        Ok(())
+    }
+}
+
+async fn write_error<W: AsyncWrite + Unpin>(writer: &mut W, error: ErrorStatus) -> Result<()> {
+    let class = match error.class {
+        ErrorClass::Auth => 0,
+        ErrorClass::Protocol => 1,
+        ErrorClass::Sqlite => 2,
+        ErrorClass::Policy => 3,
+        ErrorClass::Interrupted => 4,
+        ErrorClass::Timeout => 5,
+    };
+    writer.write_u8(class).await?;
+    writer.write_i32(error.code).await?;
+    writer.write_i32(error.extended_code).await?;
+    match error.offset {
+        Some(offset) => {
+            writer.write_u8(1).await?;
+            writer.write_len(offset).await?;
+        }
+        None => {
+            writer.write_u8(0).await?;
+        }
+    }
+    writer.write_string(error.message).await?;
+    Ok(())
+}
+
+async fn read_error<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<ErrorStatus> {
+    let class = match reader.read_u8().await? {
+        0 => ErrorClass::Auth,
+        1 => ErrorClass::Protocol,
+        2 => ErrorClass::Sqlite,
+        3 => ErrorClass::Policy,
+        4 => ErrorClass::Interrupted,
+        5 => ErrorClass::Timeout,
+        n => return Err(Error::UnknownErrorClass(n)),
+    };
+    let code = reader.read_i32().await?;
+    let extended_code = reader.read_i32().await?;
+    let offset = match reader.read_u8().await? {
+        0 => None,
+        _ => Some(reader.read_len().await?),
+    };
+    let message = reader.read_string(limits).await?;
+    Ok(ErrorStatus {
+        class,
+        code,
+        extended_code,
+        offset,
+        message,
+    })
+}
+
 #[derive(Debug)]
 pub enum Status {
     Ok,
-    Err(String),
+    Err(ErrorStatus),
 }
 
 impl Status {
     #[inline]
-    pub fn to_result(self) -> Result<(), String> {
+    pub fn to_result(self) -> Result<(), ErrorStatus> {
         match self {
             Status::Ok => Ok(()),
             Status::Err(err) => Err(err),
@@ -198,39 +581,275 @@ impl Status {
     }
 }
 
-pub async fn write_status<W: AsyncWrite + Unpin>(writer: &mut W, status: Status) -> Result<()> {
+async fn write_status_inner<W: AsyncWrite + Unpin>(writer: &mut W, status: Status) -> Result<()> {
     match status {
         Status::Ok => {
             writer.write_u8(0).await?;
         }
         Status::Err(err) => {
             writer.write_u8(1).await?;
-            writer.write_string(err).await?;
+            write_error(writer, err).await?;
         }
     }
+    Ok(())
+}
+
+pub async fn write_status<W: AsyncWrite + Unpin>(writer: &mut W, status: Status) -> Result<()> {
+    write_status_inner(writer, status).await?;
     writer.flush().await?;
     Ok(())
 }
 
-pub async fn read_status<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Status> {
+pub async fn read_status<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Status> {
     match reader.read_u8().await? {
         0 => Ok(Status::Ok),
-        1 => Ok(Status::Err(reader.read_string().await?)),
+        1 => Ok(Status::Err(read_error(reader, limits).await?)),
         n => Err(Error::UnknownStatus(n)),
     }
 }
 
+/// Whether the session is inside a transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TransactionState {
+    /// Autocommit mode, each statement runs in its own transaction
+    #[default]
+    Idle,
+    Active,
+}
+
+/// The status of a command, followed by the transaction state of the session once handled
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: Status,
+    state: TransactionState,
+) -> Result<()> {
+    write_status_inner(writer, status).await?;
+    let state = match state {
+        TransactionState::Idle => 0,
+        TransactionState::Active => 1,
+    };
+    writer.write_u8(state).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_response<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<(Status, TransactionState)> {
+    let status = read_status(reader, limits).await?;
+    let state = match reader.read_u8().await? {
+        0 => TransactionState::Idle,
+        1 => TransactionState::Active,
+        n => return Err(Error::UnknownTransactionState(n)),
+    };
+    Ok((status, state))
+}
+
+/// How a transaction acquires its locks, see `BEGIN` in the SQLite documentation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TransactionMode {
+    #[default]
+    Deferred,
+    Immediate,
+    Exclusive,
+}
+
+/// A run-time limit of the session connection, see `sqlite3_limit` in the SQLite documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// Size of a string, BLOB or table row in bytes
+    Length,
+    /// Length of an SQL statement in bytes
+    SqlLength,
+    /// Columns of a table, index, result set, `ORDER BY` or `GROUP BY`
+    Column,
+    ExprDepth,
+    /// Terms of a compound `SELECT`
+    CompoundSelect,
+    /// Virtual machine instructions of a statement
+    VdbeOp,
+    FunctionArg,
+    Attached,
+    LikePatternLength,
+    /// Index of a statement parameter
+    VariableNumber,
+    TriggerDepth,
+    WorkerThreads,
+}
+
+impl Limit {
+    pub const ALL: [Limit; 12] = [
+        Limit::Length,
+        Limit::SqlLength,
+        Limit::Column,
+        Limit::ExprDepth,
+        Limit::CompoundSelect,
+        Limit::VdbeOp,
+        Limit::FunctionArg,
+        Limit::Attached,
+        Limit::LikePatternLength,
+        Limit::VariableNumber,
+        Limit::TriggerDepth,
+        Limit::WorkerThreads,
+    ];
+
+    /// Name of the limit without the `SQLITE_LIMIT_` prefix, in lowercase
+    pub fn name(self) -> &'static str {
+        match self {
+            Limit::Length => "length",
+            Limit::SqlLength => "sql_length",
+            Limit::Column => "column",
+            Limit::ExprDepth => "expr_depth",
+            Limit::CompoundSelect => "compound_select",
+            Limit::VdbeOp => "vdbe_op",
+            Limit::FunctionArg => "function_arg",
+            Limit::Attached => "attached",
+            Limit::LikePatternLength => "like_pattern_length",
+            Limit::VariableNumber => "variable_number",
+            Limit::TriggerDepth => "trigger_depth",
+            Limit::WorkerThreads => "worker_threads",
+        }
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An option of the session connection, see `sqlite3_db_config` in the SQLite documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DbConfig {
+    /// Foreign key constraints are enforced
+    EnableFkey,
+    EnableTrigger,
+    EnableView,
+    /// Statements that could corrupt the database file are refused
+    Defensive,
+    /// SQL functions and virtual tables may be used by the schema without being innocuous
+    TrustedSchema,
+    /// Double-quoted strings are accepted in DML statements
+    DqsDml,
+    /// Double-quoted strings are accepted in DDL statements
+    DqsDdl,
+}
+
+impl DbConfig {
+    pub const ALL: [DbConfig; 7] = [
+        DbConfig::EnableFkey,
+        DbConfig::EnableTrigger,
+        DbConfig::EnableView,
+        DbConfig::Defensive,
+        DbConfig::TrustedSchema,
+        DbConfig::DqsDml,
+        DbConfig::DqsDdl,
+    ];
+
+    /// Name of the option without the `SQLITE_DBCONFIG_` prefix, in lowercase
+    pub fn name(self) -> &'static str {
+        match self {
+            DbConfig::EnableFkey => "enable_fkey",
+            DbConfig::EnableTrigger => "enable_trigger",
+            DbConfig::EnableView => "enable_view",
+            DbConfig::Defensive => "defensive",
+            DbConfig::TrustedSchema => "trusted_schema",
+            DbConfig::DqsDml => "dqs_dml",
+            DbConfig::DqsDdl => "dqs_ddl",
+        }
+    }
+}
+
+impl std::fmt::Display for DbConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Command {
     Ping,
     Disconnect,
-    SimpleExecute { sql: String },
-    SimpleQuery { sql: String },
-    Transaction { sqls: Vec<String> },
-    // SetDbConfig
-    // SetLimit
-    // LoadExtension
-    // Prepare
+    /// `timeout` in milliseconds overrides the server default time limit,
+    /// though the server may lower it to its own limit
+    SimpleExecute {
+        sql: String,
+        params: Parameters,
+        timeout: Option<u64>,
+    },
+    SimpleQuery {
+        sql: String,
+        params: Parameters,
+        timeout: Option<u64>,
+    },
+    Transaction {
+        sqls: Vec<String>,
+        timeout: Option<u64>,
+    },
+    Prepare {
+        sql: String,
+    },
+    ExecutePrepared {
+        stmt: u64,
+        params: Parameters,
+    },
+    QueryPrepared {
+        stmt: u64,
+        params: Parameters,
+    },
+    ClosePrepared {
+        stmt: u64,
+    },
+    OpenCursor {
+        sql: String,
+        params: Parameters,
+    },
+    Fetch {
+        cursor: u64,
+        max_rows: u64,
+    },
+    CloseCursor {
+        cursor: u64,
+    },
+    Script {
+        sql: String,
+    },
+    Begin {
+        mode: TransactionMode,
+    },
+    Commit,
+    Rollback,
+    Savepoint {
+        name: String,
+    },
+    Release {
+        name: String,
+    },
+    RollbackTo {
+        name: String,
+    },
+    /// Loads an extension the server allows by `name`, calling `entry_point` if given
+    /// or the one SQLite derives from the file name
+    LoadExtension {
+        name: String,
+        entry_point: Option<String>,
+    },
+    /// Turns `option` on or off if `enabled` is given, unless the server pinned it.
+    /// Answered with whether the option is on
+    SetDbConfig {
+        option: DbConfig,
+        enabled: Option<bool>,
+    },
+    /// Lowers or raises `limit` to `value` if given, though the server may lower it
+    /// to its own ceiling. Answered with the value of the limit in effect
+    SetLimit {
+        limit: Limit,
+        value: Option<u64>,
+    },
 }
 
 pub async fn write_command<W: AsyncWrite + Unpin>(writer: &mut W, cmd: Command) -> Result<()> {
@@ -241,59 +860,427 @@ pub async fn write_command<W: AsyncWrite + Unpin>(writer: &mut W, cmd: Command)
         Command::Disconnect => {
             writer.write_u8(1).await?;
         }
-        Command::SimpleExecute { sql } => {
+        Command::SimpleExecute {
+            sql,
+            params,
+            timeout,
+        } => {
             writer.write_u8(2).await?;
             writer.write_string(sql).await?;
+            write_parameters(writer, &params).await?;
+            write_timeout(writer, timeout).await?;
         }
-        Command::SimpleQuery { sql } => {
+        Command::SimpleQuery {
+            sql,
+            params,
+            timeout,
+        } => {
             writer.write_u8(3).await?;
             writer.write_string(sql).await?;
+            write_parameters(writer, &params).await?;
+            write_timeout(writer, timeout).await?;
         }
-        Command::Transaction { sqls } => {
+        Command::Transaction { sqls, timeout } => {
             writer.write_u8(4).await?;
             writer.write_len(sqls.len() as u64).await?;
             for sql in sqls {
                 writer.write_string(sql).await?;
             }
+            write_timeout(writer, timeout).await?;
+        }
+        Command::Prepare { sql } => {
+            writer.write_u8(5).await?;
+            writer.write_string(sql).await?;
+        }
+        Command::ExecutePrepared { stmt, params } => {
+            writer.write_u8(6).await?;
+            writer.write_len(stmt).await?;
+            write_parameters(writer, &params).await?;
+        }
+        Command::QueryPrepared { stmt, params } => {
+            writer.write_u8(7).await?;
+            writer.write_len(stmt).await?;
+            write_parameters(writer, &params).await?;
+        }
+        Command::ClosePrepared { stmt } => {
+            writer.write_u8(8).await?;
+            writer.write_len(stmt).await?;
+        }
+        Command::OpenCursor { sql, params } => {
+            writer.write_u8(9).await?;
+            writer.write_string(sql).await?;
+            write_parameters(writer, &params).await?;
+        }
+        Command::Fetch { cursor, max_rows } => {
+            writer.write_u8(10).await?;
+            writer.write_len(cursor).await?;
+            writer.write_len(max_rows).await?;
+        }
+        Command::CloseCursor { cursor } => {
+            writer.write_u8(11).await?;
+            writer.write_len(cursor).await?;
+        }
+        Command::Script { sql } => {
+            writer.write_u8(12).await?;
+            writer.write_string(sql).await?;
+        }
+        Command::Begin { mode } => {
+            writer.write_u8(13).await?;
+            let mode = match mode {
+                TransactionMode::Deferred => 0,
+                TransactionMode::Immediate => 1,
+                TransactionMode::Exclusive => 2,
+            };
+            writer.write_u8(mode).await?;
+        }
+        Command::Commit => {
+            writer.write_u8(14).await?;
+        }
+        Command::Rollback => {
+            writer.write_u8(15).await?;
+        }
+        Command::Savepoint { name } => {
+            writer.write_u8(16).await?;
+            writer.write_string(name).await?;
+        }
+        Command::Release { name } => {
+            writer.write_u8(17).await?;
+            writer.write_string(name).await?;
+        }
+        Command::RollbackTo { name } => {
+            writer.write_u8(18).await?;
+            writer.write_string(name).await?;
+        }
+        Command::LoadExtension { name, entry_point } => {
+            writer.write_u8(19).await?;
+            writer.write_string(name).await?;
+            match entry_point {
+                Some(entry_point) => {
+                    writer.write_u8(1).await?;
+                    writer.write_string(entry_point).await?;
+                }
+                None => {
+                    writer.write_u8(0).await?;
+                }
+            }
+        }
+        Command::SetLimit { limit, value } => {
+            writer.write_u8(20).await?;
+            let limit = match limit {
+                Limit::Length => 0,
+                Limit::SqlLength => 1,
+                Limit::Column => 2,
+                Limit::ExprDepth => 3,
+                Limit::CompoundSelect => 4,
+                Limit::VdbeOp => 5,
+                Limit::FunctionArg => 6,
+                Limit::Attached => 7,
+                Limit::LikePatternLength => 8,
+                Limit::VariableNumber => 9,
+                Limit::TriggerDepth => 10,
+                Limit::WorkerThreads => 11,
+            };
+            writer.write_u8(limit).await?;
+            match value {
+                Some(value) => {
+                    writer.write_u8(1).await?;
+                    writer.write_len(value).await?;
+                }
+                None => {
+                    writer.write_u8(0).await?;
+                }
+            }
+        }
+        Command::SetDbConfig { option, enabled } => {
+            writer.write_u8(21).await?;
+            let option = match option {
+                DbConfig::EnableFkey => 0,
+                DbConfig::EnableTrigger => 1,
+                DbConfig::EnableView => 2,
+                DbConfig::Defensive => 3,
+                DbConfig::TrustedSchema => 4,
+                DbConfig::DqsDml => 5,
+                DbConfig::DqsDdl => 6,
+            };
+            writer.write_u8(option).await?;
+            let enabled = match enabled {
+                None => 0,
+                Some(false) => 1,
+                Some(true) => 2,
+            };
+            writer.write_u8(enabled).await?;
         }
     }
     writer.flush().await?;
     Ok(())
 }
 
-pub async fn read_command<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Command> {
+/// Time limit of a command in milliseconds, `None` for the server default
+async fn write_timeout<W: AsyncWrite + Unpin>(writer: &mut W, timeout: Option<u64>) -> Result<()> {
+    match timeout {
+        Some(timeout) => {
+            writer.write_u8(1).await?;
+            writer.write_len(timeout).await?;
+        }
+        None => {
+            writer.write_u8(0).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn read_timeout<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<u64>> {
+    match reader.read_u8().await? {
+        0 => Ok(None),
+        _ => Ok(Some(reader.read_len().await?)),
+    }
+}
+
+pub async fn read_command<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Command> {
     let cmd = match reader.read_u8().await? {
         0 => Command::Ping,
         1 => Command::Disconnect,
         2 => {
-            let sql = reader.read_string().await?;
-            Command::SimpleExecute { sql }
+            let sql = reader.read_sql(limits).await?;
+            let params = read_parameters(reader, limits).await?;
+            let timeout = read_timeout(reader).await?;
+            Command::SimpleExecute {
+                sql,
+                params,
+                timeout,
+            }
         }
         3 => {
-            let sql = reader.read_string().await?;
-            Command::SimpleQuery { sql }
+            let sql = reader.read_sql(limits).await?;
+            let params = read_parameters(reader, limits).await?;
+            let timeout = read_timeout(reader).await?;
+            Command::SimpleQuery {
+                sql,
+                params,
+                timeout,
+            }
         }
         4 => {
-            let len = reader.read_len().await? as usize;
-            let mut sqls = Vec::with_capacity(len);
+            let len = reader
+                .read_count("Transaction", limits.max_statements)
+                .await?;
+            let mut sqls = Vec::with_capacity(capacity(len));
             for _ in 0..len {
-                sqls.push(reader.read_string().await?);
+                sqls.push(reader.read_sql(limits).await?);
             }
-            Command::Transaction { sqls }
+            let timeout = read_timeout(reader).await?;
+            Command::Transaction { sqls, timeout }
+        }
+        5 => {
+            let sql = reader.read_sql(limits).await?;
+            Command::Prepare { sql }
+        }
+        6 => {
+            let stmt = reader.read_len().await?;
+            let params = read_parameters(reader, limits).await?;
+            Command::ExecutePrepared { stmt, params }
+        }
+        7 => {
+            let stmt = reader.read_len().await?;
+            let params = read_parameters(reader, limits).await?;
+            Command::QueryPrepared { stmt, params }
+        }
+        8 => {
+            let stmt = reader.read_len().await?;
+            Command::ClosePrepared { stmt }
+        }
+        9 => {
+            let sql = reader.read_sql(limits).await?;
+            let params = read_parameters(reader, limits).await?;
+            Command::OpenCursor { sql, params }
+        }
+        10 => {
+            let cursor = reader.read_len().await?;
+            let max_rows = reader.read_len().await?;
+            Command::Fetch { cursor, max_rows }
+        }
+        11 => {
+            let cursor = reader.read_len().await?;
+            Command::CloseCursor { cursor }
+        }
+        12 => {
+            let sql = reader.read_sql(limits).await?;
+            Command::Script { sql }
+        }
+        13 => {
+            let mode = match reader.read_u8().await? {
+                0 => TransactionMode::Deferred,
+                1 => TransactionMode::Immediate,
+                2 => TransactionMode::Exclusive,
+                n => return Err(Error::UnknownTransactionMode(n)),
+            };
+            Command::Begin { mode }
+        }
+        14 => Command::Commit,
+        15 => Command::Rollback,
+        16 => {
+            let name = reader.read_string(limits).await?;
+            Command::Savepoint { name }
+        }
+        17 => {
+            let name = reader.read_string(limits).await?;
+            Command::Release { name }
+        }
+        18 => {
+            let name = reader.read_string(limits).await?;
+            Command::RollbackTo { name }
+        }
+        19 => {
+            let name = reader.read_string(limits).await?;
+            let entry_point = match reader.read_u8().await? {
+                0 => None,
+                _ => Some(reader.read_string(limits).await?),
+            };
+            Command::LoadExtension { name, entry_point }
+        }
+        20 => {
+            let limit = match reader.read_u8().await? {
+                0 => Limit::Length,
+                1 => Limit::SqlLength,
+                2 => Limit::Column,
+                3 => Limit::ExprDepth,
+                4 => Limit::CompoundSelect,
+                5 => Limit::VdbeOp,
+                6 => Limit::FunctionArg,
+                7 => Limit::Attached,
+                8 => Limit::LikePatternLength,
+                9 => Limit::VariableNumber,
+                10 => Limit::TriggerDepth,
+                11 => Limit::WorkerThreads,
+                n => return Err(Error::UnknownLimit(n)),
+            };
+            let value = match reader.read_u8().await? {
+                0 => None,
+                _ => Some(reader.read_len().await?),
+            };
+            Command::SetLimit { limit, value }
+        }
+        21 => {
+            let option = match reader.read_u8().await? {
+                0 => DbConfig::EnableFkey,
+                1 => DbConfig::EnableTrigger,
+                2 => DbConfig::EnableView,
+                3 => DbConfig::Defensive,
+                4 => DbConfig::TrustedSchema,
+                5 => DbConfig::DqsDml,
+                6 => DbConfig::DqsDdl,
+                n => return Err(Error::UnknownDbConfig(n)),
+            };
+            let enabled = match reader.read_u8().await? {
+                0 => None,
+                1 => Some(false),
+                _ => Some(true),
+            };
+            Command::SetDbConfig { option, enabled }
         }
         other => return Err(Error::UnknownCommand(other)),
     };
     Ok(cmd)
 }
 
+/// Value of a limit in effect, in answer to [`Command::SetLimit`]
+pub async fn write_limit<W: AsyncWrite + Unpin>(writer: &mut W, value: u64) -> Result<()> {
+    writer.write_len(value).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_limit<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u64> {
+    let value = reader.read_len().await?;
+    Ok(value)
+}
+
+/// Whether an option is on, in answer to [`Command::SetDbConfig`]
+pub async fn write_db_config<W: AsyncWrite + Unpin>(writer: &mut W, enabled: bool) -> Result<()> {
+    writer.write_u8(enabled as u8).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_db_config<R: AsyncRead + Unpin>(reader: &mut R) -> Result<bool> {
+    let enabled = reader.read_u8().await?;
+    Ok(enabled != 0)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Query {
     pub columns: Vec<Column>,
     pub values: Vec<Value>,
     pub rows_affected: u64,
+    pub total_changes: u64,
+    pub last_insert_rowid: i64,
     pub duration: u64,
 }
 
+/// Change counts of the connection after a statement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Changes {
+    /// Rows modified by the statement itself, see `sqlite3_changes`
+    pub rows_affected: u64,
+    /// Rows modified since the connection was opened, see `sqlite3_total_changes`
+    pub total_changes: u64,
+    pub last_insert_rowid: i64,
+}
+
+async fn write_changes_inner<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    changes: Changes,
+) -> Result<()> {
+    writer.write_len(changes.rows_affected).await?;
+    writer.write_len(changes.total_changes).await?;
+    writer.write_i64(changes.last_insert_rowid).await?;
+    Ok(())
+}
+
+pub async fn write_changes<W: AsyncWrite + Unpin>(writer: &mut W, changes: Changes) -> Result<()> {
+    write_changes_inner(writer, changes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_changes<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Changes> {
+    Ok(Changes {
+        rows_affected: reader.read_len().await?,
+        total_changes: reader.read_len().await?,
+        last_insert_rowid: reader.read_i64().await?,
+    })
+}
+
+/// Changes of each statement of a transaction
+pub async fn write_transaction<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    changes: Vec<Changes>,
+) -> Result<()> {
+    writer.write_len(changes.len() as u64).await?;
+    for changes in changes {
+        write_changes_inner(writer, changes).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_transaction<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Vec<Changes>> {
+    let len = reader
+        .read_count("Transaction", limits.max_statements)
+        .await?;
+    let mut changes = Vec::with_capacity(capacity(len));
+    for _ in 0..len {
+        changes.push(read_changes(reader).await?);
+    }
+    Ok(changes)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Column {
     pub name: String,
@@ -309,120 +1296,618 @@ pub enum Value {
     Text(Vec<u8>),
 }
 
-async fn write_columns<W: AsyncWrite + Unpin>(writer: &mut W, columns: &[Column]) -> Result<()> {
+macro_rules! impl_from_value {
+    ($variant:ident: $($ty:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    Value::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_value!(I64: i64, i32, i16, u32, u16, bool);
+impl_from_value!(F64: f64, f32);
+impl_from_value!(Text: String, &str);
+impl_from_value!(Bytes: Vec<u8>, &[u8]);
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+pub async fn write_columns<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    columns: &[Column],
+) -> Result<()> {
     writer.write_len(columns.len() as u64).await?;
     for column in columns {
         writer.write_string(&column.name).await?;
         writer.write_string(&column.datatype).await?;
     }
+    writer.flush().await?;
     Ok(())
 }
 
-async fn read_columns<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<Column>> {
-    let len = reader.read_len().await? as usize;
-    let mut columns = Vec::with_capacity(len);
+pub async fn read_columns<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Vec<Column>> {
+    let len = reader.read_count("Columns", limits.max_values).await?;
+    let mut columns = Vec::with_capacity(capacity(len));
     for _ in 0..len {
-        let name = reader.read_string().await?;
-        let datatype = reader.read_string().await?;
+        let name = reader.read_string(limits).await?;
+        let datatype = reader.read_string(limits).await?;
         columns.push(Column { name, datatype });
     }
     Ok(columns)
 }
 
+async fn write_value<W: AsyncWrite + Unpin>(writer: &mut W, value: &Value) -> Result<()> {
+    match value {
+        Value::Null => {
+            writer.write_u8(0).await?;
+        }
+        Value::I64(v) => {
+            if *v >= 0 {
+                writer.write_u8(1).await?;
+                writer.write_len(*v as u64).await?;
+            } else {
+                writer.write_u8(2).await?;
+                let encoded = ((*v << 1) ^ (*v >> 63)) as u64; // ZigZag
+                writer.write_len(encoded).await?;
+            }
+        }
+        Value::F64(v) => {
+            writer.write_u8(3).await?;
+            writer.write_f64(*v).await?;
+        }
+        Value::Bytes(v) => {
+            if v.is_empty() {
+                writer.write_u8(4).await?;
+            } else {
+                writer.write_u8(5).await?;
+                writer.write_bytes(v).await?;
+            }
+        }
+        Value::Text(v) => {
+            if v.is_empty() {
+                writer.write_u8(6).await?;
+            } else {
+                writer.write_u8(7).await?;
+                writer.write_bytes(v).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn write_values<W: AsyncWrite + Unpin>(writer: &mut W, values: &[Value]) -> Result<()> {
     writer.write_len(values.len() as u64).await?;
     for value in values {
-        match value {
-            Value::Null => {
-                writer.write_u8(0).await?;
+        write_value(writer, value).await?;
+    }
+    Ok(())
+}
+
+async fn read_value<R: AsyncRead + Unpin>(reader: &mut R, limits: &DecodeLimits) -> Result<Value> {
+    let value = match reader.read_u8().await? {
+        0 => Value::Null,
+        1 => Value::I64(reader.read_len().await? as i64),
+        2 => {
+            let encoded = reader.read_len().await?;
+            let decoded = ((encoded >> 1) as i64) ^ -((encoded & 1) as i64); // ZigZag
+            Value::I64(decoded)
+        }
+        3 => Value::F64(reader.read_f64().await?),
+        4 => Value::Bytes(Vec::new()),
+        5 => Value::Bytes(reader.read_bytes(limits).await?),
+        6 => Value::Text(Vec::new()),
+        7 => Value::Text(reader.read_bytes(limits).await?),
+        type_id => return Err(Error::UnknownValue(type_id)),
+    };
+    Ok(value)
+}
+
+async fn read_values<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Vec<Value>> {
+    let len = reader.read_count("Values", limits.max_values).await?;
+    let mut values = Vec::with_capacity(capacity(len));
+    for _ in 0..len {
+        values.push(read_value(reader, limits).await?);
+    }
+    Ok(values)
+}
+
+/// Reads values that must form whole rows of `columns` values
+async fn read_rows<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    columns: usize,
+    limits: &DecodeLimits,
+) -> Result<Vec<Value>> {
+    let values = read_values(reader, limits).await?;
+    if columns == 0 && !values.is_empty() {
+        return Err(Error::InvalidValuesLength(values.len(), 0));
+    }
+    if !values.is_empty() && values.len() % columns != 0 {
+        return Err(Error::InvalidValuesLength(values.len(), columns));
+    }
+    Ok(values)
+}
+
+/// Parameters bound to a statement, either by position (`?`, `?NNN`)
+/// or by name (`:name`, `@name`, `$name`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parameters {
+    Positional(Vec<Value>),
+    Named(Vec<(String, Value)>),
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Parameters::Positional(Vec::new())
+    }
+}
+
+impl Parameters {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Parameters::Positional(values) => values.is_empty(),
+            Parameters::Named(values) => values.is_empty(),
+        }
+    }
+}
+
+impl<V: Into<Value>> From<Vec<V>> for Parameters {
+    fn from(values: Vec<V>) -> Self {
+        Parameters::Positional(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<V: Into<Value>, const N: usize> From<[V; N]> for Parameters {
+    fn from(values: [V; N]) -> Self {
+        Parameters::Positional(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> From<Vec<(K, V)>> for Parameters {
+    fn from(values: Vec<(K, V)>) -> Self {
+        Parameters::Named(
+            values
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>, const N: usize> From<[(K, V); N]> for Parameters {
+    fn from(values: [(K, V); N]) -> Self {
+        Parameters::Named(
+            values
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+async fn write_parameters<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    params: &Parameters,
+) -> Result<()> {
+    match params {
+        Parameters::Positional(values) => {
+            writer.write_u8(0).await?;
+            write_values(writer, values).await?;
+        }
+        Parameters::Named(values) => {
+            writer.write_u8(1).await?;
+            writer.write_len(values.len() as u64).await?;
+            for (name, value) in values {
+                writer.write_string(name).await?;
+                write_value(writer, value).await?;
             }
-            Value::I64(v) => {
-                if *v >= 0 {
-                    writer.write_u8(1).await?;
-                    writer.write_len(*v as u64).await?;
-                } else {
-                    writer.write_u8(2).await?;
-                    let encoded = ((*v << 1) ^ (*v >> 63)) as u64; // ZigZag
-                    writer.write_len(encoded).await?;
-                }
+        }
+    }
+    Ok(())
+}
+
+async fn read_parameters<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Parameters> {
+    match reader.read_u8().await? {
+        0 => Ok(Parameters::Positional(read_values(reader, limits).await?)),
+        1 => {
+            let len = reader.read_count("Parameters", limits.max_values).await?;
+            let mut values = Vec::with_capacity(capacity(len));
+            for _ in 0..len {
+                let name = reader.read_string(limits).await?;
+                let value = read_value(reader, limits).await?;
+                values.push((name, value));
             }
-            Value::F64(v) => {
-                writer.write_u8(3).await?;
-                writer.write_f64(*v).await?;
+            Ok(Parameters::Named(values))
+        }
+        n => Err(Error::UnknownParameters(n)),
+    }
+}
+
+/// A statement prepared on the server, referenced by its handle
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prepared {
+    pub stmt: u64,
+    /// One entry per parameter, `None` for anonymous parameters (`?`)
+    pub parameters: Vec<Option<String>>,
+}
+
+pub async fn write_prepared<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    prepared: Prepared,
+) -> Result<()> {
+    writer.write_len(prepared.stmt).await?;
+    writer.write_len(prepared.parameters.len() as u64).await?;
+    for name in prepared.parameters {
+        match name {
+            Some(name) => {
+                writer.write_u8(1).await?;
+                writer.write_string(name).await?;
             }
-            Value::Bytes(v) => {
-                if v.is_empty() {
-                    writer.write_u8(4).await?;
-                } else {
-                    writer.write_u8(5).await?;
-                    writer.write_bytes(v).await?;
-                }
+            None => {
+                writer.write_u8(0).await?;
             }
-            Value::Text(v) => {
-                if v.is_empty() {
-                    writer.write_u8(6).await?;
-                } else {
-                    writer.write_u8(7).await?;
-                    writer.write_bytes(v).await?;
-                }
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_prepared<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Prepared> {
+    let stmt = reader.read_len().await?;
+    let len = reader.read_count("Parameters", limits.max_values).await?;
+    let mut parameters = Vec::with_capacity(capacity(len));
+    for _ in 0..len {
+        let name = match reader.read_u8().await? {
+            0 => None,
+            _ => Some(reader.read_string(limits).await?),
+        };
+        parameters.push(name);
+    }
+    Ok(Prepared { stmt, parameters })
+}
+
+/// Result of one statement of a script
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementResult {
+    Rows {
+        columns: Vec<Column>,
+        values: Vec<Value>,
+    },
+    Execute(Changes),
+}
+
+/// The statement a script stopped at, `offset` is its byte offset in the script
+/// and the offset of `error` is relative to the script too
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptError {
+    pub offset: u64,
+    pub error: ErrorStatus,
+}
+
+/// Results of the statements of a script in order, up to the failed statement if any
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub results: Vec<StatementResult>,
+    pub error: Option<ScriptError>,
+}
+
+pub async fn write_script<W: AsyncWrite + Unpin>(writer: &mut W, script: Script) -> Result<()> {
+    writer.write_len(script.results.len() as u64).await?;
+    for result in script.results {
+        match result {
+            StatementResult::Rows { columns, values } => {
+                writer.write_u8(0).await?;
+                write_columns(writer, &columns).await?;
+                write_values(writer, &values).await?;
+            }
+            StatementResult::Execute(changes) => {
+                writer.write_u8(1).await?;
+                write_changes_inner(writer, changes).await?;
             }
         }
     }
+    match script.error {
+        Some(error) => {
+            writer.write_u8(1).await?;
+            writer.write_len(error.offset).await?;
+            write_error(writer, error.error).await?;
+        }
+        None => {
+            writer.write_u8(0).await?;
+        }
+    }
+    writer.flush().await?;
     Ok(())
 }
 
-async fn read_values<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<Value>> {
-    let len = reader.read_len().await? as usize;
-    let mut values = Vec::with_capacity(len);
+pub async fn read_script<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Script> {
+    let len = reader.read_count("Script", limits.max_statements).await?;
+    let mut results = Vec::with_capacity(capacity(len));
+    // The values of all the statements are bounded together
+    let mut total = 0;
     for _ in 0..len {
-        let type_id = reader.read_u8().await?;
-        let value = match type_id {
-            0 => Value::Null,
-            1 => Value::I64(reader.read_len().await? as i64),
-            2 => {
-                let encoded = reader.read_len().await?;
-                let decoded = ((encoded >> 1) as i64) ^ -((encoded & 1) as i64); // ZigZag
-                Value::I64(decoded)
+        let result = match reader.read_u8().await? {
+            0 => {
+                let columns = read_columns(reader, limits).await?;
+                let values = read_rows(reader, columns.len(), limits).await?;
+                total += values.len() as u64;
+                if total > limits.max_values {
+                    return Err(Error::TooLarge("Script values", total, limits.max_values));
+                }
+                StatementResult::Rows { columns, values }
             }
-            3 => Value::F64(reader.read_f64().await?),
-            4 => Value::Bytes(Vec::new()),
-            5 => Value::Bytes(reader.read_bytes().await?),
-            6 => Value::Text(Vec::new()),
-            7 => Value::Text(reader.read_bytes().await?),
-            type_id => return Err(Error::UnknownValue(type_id)),
+            1 => StatementResult::Execute(read_changes(reader).await?),
+            n => return Err(Error::UnknownStatementResult(n)),
         };
-        values.push(value);
+        results.push(result);
     }
-    Ok(values)
+    let error = match reader.read_u8().await? {
+        0 => None,
+        _ => Some(ScriptError {
+            offset: reader.read_len().await?,
+            error: read_error(reader, limits).await?,
+        }),
+    };
+    Ok(Script { results, error })
 }
 
-pub async fn write_query<W: AsyncWrite + Unpin>(writer: &mut W, query: Query) -> Result<()> {
-    write_columns(writer, &query.columns).await?;
-    write_values(writer, &query.values).await?;
-    writer.write_len(query.rows_affected).await?;
-    writer.write_len(query.duration).await?;
+/// A cursor opened on the server, referenced by its handle
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub cursor: u64,
+    pub columns: Vec<Column>,
+}
+
+pub async fn write_cursor<W: AsyncWrite + Unpin>(writer: &mut W, cursor: Cursor) -> Result<()> {
+    writer.write_len(cursor.cursor).await?;
+    write_columns(writer, &cursor.columns).await?;
+    Ok(())
+}
+
+pub async fn read_cursor<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: &DecodeLimits,
+) -> Result<Cursor> {
+    let cursor = reader.read_len().await?;
+    let columns = read_columns(reader, limits).await?;
+    Ok(Cursor { cursor, columns })
+}
+
+/// Rows fetched from a cursor, `done` once the cursor is exhausted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetched {
+    pub values: Vec<Value>,
+    pub done: bool,
+}
+
+pub async fn write_fetched<W: AsyncWrite + Unpin>(writer: &mut W, fetched: Fetched) -> Result<()> {
+    write_values(writer, &fetched.values).await?;
+    writer.write_u8(fetched.done as u8).await?;
     writer.flush().await?;
     Ok(())
 }
 
-pub async fn read_query<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Query> {
-    let columns = read_columns(reader).await?;
-    let values = read_values(reader).await?;
-    let rows_affected = reader.read_len().await?;
-    let duration = reader.read_len().await?;
-    if columns.is_empty() && !values.is_empty() {
-        return Err(Error::InvalidValuesLength(values.len(), 0));
+pub async fn read_fetched<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    columns: usize,
+    limits: &DecodeLimits,
+) -> Result<Fetched> {
+    let values = read_rows(reader, columns, limits).await?;
+    let done = reader.read_u8().await? != 0;
+    Ok(Fetched { values, done })
+}
+
+/// One frame of a streamed query result, following its columns
+#[derive(Debug, Clone, PartialEq)]
+pub enum Batch {
+    Rows(Vec<Value>),
+    Done { changes: Changes, duration: u64 },
+    Err(ErrorStatus),
+}
+
+pub async fn write_batch<W: AsyncWrite + Unpin>(writer: &mut W, batch: Batch) -> Result<()> {
+    match batch {
+        Batch::Rows(values) => {
+            writer.write_u8(0).await?;
+            write_values(writer, &values).await?;
+        }
+        Batch::Done { changes, duration } => {
+            writer.write_u8(1).await?;
+            write_changes_inner(writer, changes).await?;
+            writer.write_len(duration).await?;
+        }
+        Batch::Err(err) => {
+            writer.write_u8(2).await?;
+            write_error(writer, err).await?;
+        }
     }
-    if !values.is_empty() && values.len() % columns.len() != 0 {
-        return Err(Error::InvalidValuesLength(values.len(), columns.len()));
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_batch<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    columns: usize,
+    limits: &DecodeLimits,
+) -> Result<Batch> {
+    match reader.read_u8().await? {
+        0 => Ok(Batch::Rows(read_rows(reader, columns, limits).await?)),
+        1 => Ok(Batch::Done {
+            changes: read_changes(reader).await?,
+            duration: reader.read_len().await?,
+        }),
+        2 => Ok(Batch::Err(read_error(reader, limits).await?)),
+        n => Err(Error::UnknownBatch(n)),
     }
-    Ok(Query {
-        columns,
-        values,
-        rows_affected,
-        duration,
-    })
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn values(len: i64) -> Vec<Value> {
+        (0..len).map(Value::I64).collect()
+    }
+
+    async fn encode_values(values: &[Value]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_values(&mut buf, values).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn rows_round_trip() {
+        let limits = DecodeLimits::DEFAULT;
+        let values = vec![
+            Value::Null,
+            Value::I64(-1),
+            Value::F64(0.5),
+            Value::Bytes(vec![0, 1]),
+            Value::Text(Vec::new()),
+            Value::Text(b"echolite".to_vec()),
+        ];
+        let buf = encode_values(&values).await;
+        let rows = read_rows(&mut buf.as_slice(), 3, &limits).await.unwrap();
+        assert_eq!(rows, values);
+        let rows = read_rows(&mut encode_values(&[]).await.as_slice(), 0, &limits).await;
+        assert!(rows.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rows_over_limit() {
+        let limits = DecodeLimits {
+            max_values: 3,
+            ..DecodeLimits::DEFAULT
+        };
+        let buf = encode_values(&values(4)).await;
+        let rows = read_rows(&mut buf.as_slice(), 2, &limits).await;
+        assert!(matches!(rows, Err(Error::TooLarge("Values", 4, 3))));
+
+        let limits = DecodeLimits {
+            max_string_length: 3,
+            ..DecodeLimits::DEFAULT
+        };
+        let buf = encode_values(&[Value::Text(b"echolite".to_vec())]).await;
+        let rows = read_rows(&mut buf.as_slice(), 1, &limits).await;
+        assert!(matches!(rows, Err(Error::TooLarge("String", 8, 3))));
+    }
+
+    #[tokio::test]
+    async fn rows_partial() {
+        let limits = DecodeLimits::DEFAULT;
+        let buf = encode_values(&values(3)).await;
+        let rows = read_rows(&mut buf.as_slice(), 2, &limits).await;
+        assert!(matches!(rows, Err(Error::InvalidValuesLength(3, 2))));
+        let rows = read_rows(&mut buf.as_slice(), 0, &limits).await;
+        assert!(matches!(rows, Err(Error::InvalidValuesLength(3, 0))));
+    }
+
+    #[tokio::test]
+    async fn script_values_over_limit() {
+        let limits = DecodeLimits {
+            max_values: 5,
+            ..DecodeLimits::DEFAULT
+        };
+        let rows = StatementResult::Rows {
+            columns: vec![Column {
+                name: "n".to_string(),
+                datatype: "INTEGER".to_string(),
+            }],
+            values: values(3),
+        };
+        let mut script = Script {
+            results: vec![rows.clone()],
+            error: None,
+        };
+        let mut buf = Vec::new();
+        write_script(&mut buf, script.clone()).await.unwrap();
+        assert_eq!(
+            read_script(&mut buf.as_slice(), &limits).await.unwrap(),
+            script
+        );
+
+        // Each statement is within the limit, but not both together
+        script.results.push(rows);
+        let mut buf = Vec::new();
+        write_script(&mut buf, script).await.unwrap();
+        let script = read_script(&mut buf.as_slice(), &limits).await;
+        assert!(matches!(
+            script,
+            Err(Error::TooLarge("Script values", 6, 5))
+        ));
+    }
+
+    #[tokio::test]
+    async fn command_sql_over_limit() {
+        let limits = DecodeLimits {
+            max_sql_length: 8,
+            ..DecodeLimits::DEFAULT
+        };
+        let mut buf = Vec::new();
+        write_command(
+            &mut buf,
+            Command::Prepare {
+                sql: "SELECT 1".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        let command = read_command(&mut buf.as_slice(), &limits).await.unwrap();
+        assert!(matches!(command, Command::Prepare { sql } if sql == "SELECT 1"));
+
+        let mut buf = Vec::new();
+        let sqls = vec!["SELECT 1".to_string(), "SELECT 10".to_string()];
+        let cmd = Command::Transaction {
+            sqls,
+            timeout: None,
+        };
+        write_command(&mut buf, cmd).await.unwrap();
+        let command = read_command(&mut buf.as_slice(), &limits).await;
+        assert!(matches!(command, Err(Error::TooLarge("SQL", 9, 8))));
+    }
+
+    #[tokio::test]
+    async fn handshake_limits() {
+        let mut buf = Vec::new();
+        let user = "u".repeat(MAX_USER_LENGTH as usize);
+        write_user(&mut buf, &user).await.unwrap();
+        assert_eq!(read_user(&mut buf.as_slice()).await.unwrap(), user);
+
+        let mut buf = Vec::new();
+        write_user(&mut buf, format!("{user}u")).await.unwrap();
+        let user = read_user(&mut buf.as_slice()).await;
+        assert!(matches!(user, Err(Error::TooLarge("User", 257, 256))));
+
+        let mut buf = Vec::new();
+        let path = "p".repeat(MAX_PATH_LENGTH as usize + 1);
+        write_connect(&mut buf, path, Flags::default())
+            .await
+            .unwrap();
+        let connect = read_connect(&mut buf.as_slice()).await;
+        assert!(matches!(connect, Err(Error::TooLarge("Path", 4097, 4096))));
+    }
+}
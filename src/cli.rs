@@ -1,22 +1,159 @@
 use crate::Error;
-use clap::Parser;
-use protocol::{Params, Salt, to_hash_password};
+use crate::users::Users;
+use crate::verifier::Verifier;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use glob::Pattern;
+use protocol::{
+    DbConfig, DecodeLimits, Flags, Limit, Params, Prehash, Salt, ServerProof, consts::*,
+    to_hash_password, to_mutual_hash_password,
+};
+use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Semaphore;
 use tracing::level_filters::LevelFilter;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
 #[derive(Parser, Debug)]
-#[clap(version)]
+#[clap(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+#[clap(group(ArgGroup::new("auth").required(true).args(["password", "password_file", "users"])))]
 pub struct Args {
-    /// Set listen address
-    #[clap(short,  long, name = "ADDRESS|IP|PORT", env = "ECHOLITE_BIND", value_parser = to_socket_addr, default_value_t = DEFAULT_BIND)]
-    pub bind: SocketAddr,
+    #[clap(subcommand)]
+    pub command: Option<Subcommands>,
+
+    /// Set listen address, or `unix:<PATH>` to listen on a Unix domain socket
+    #[clap(short,  long, name = "ADDRESS|IP|PORT|unix:PATH", env = "ECHOLITE_BIND", value_parser = to_bind, default_value_t = Bind::Tcp(DEFAULT_BIND))]
+    pub bind: Bind,
+
+    /// Set the octal permissions of the Unix domain socket file
+    #[cfg(unix)]
+    #[clap(long, env = "ECHOLITE_SOCKET_MODE", value_parser = to_mode, default_value = "660")]
+    pub socket_mode: u32,
 
     /// Set auth password
     #[clap(short, long, env = "ECHOLITE_PASSWORD", value_parser = Password::from_str)]
-    pub password: Password,
+    pub password: Option<Password>,
+
+    /// Read the auth password, or its hash as printed by `echolite hash-password`, from a file.
+    /// The hash is enough to log in, keep the file as secret as the password
+    #[clap(long, env = "ECHOLITE_PASSWORD_FILE", value_parser = Password::from_file)]
+    pub password_file: Option<Password>,
+
+    /// Set the TOML users file, listing the users allowed to connect instead of a single password
+    #[clap(long, env = "ECHOLITE_USERS", value_parser = Users::load)]
+    pub users: Option<Arc<Users>>,
+
+    /// Set the directory database paths are resolved against, none may lead out of it
+    #[clap(long, env = "ECHOLITE_ROOT", value_parser = to_root)]
+    pub root: Option<PathBuf>,
+
+    /// Only allow database paths under the root directory matching one of these glob patterns
+    #[clap(long, env = "ECHOLITE_ALLOW", value_delimiter = ',', requires = "root")]
+    pub allow: Vec<Pattern>,
+
+    #[clap(flatten)]
+    pub open_policy: OpenPolicy,
+
+    /// Allow clients to load the SQLite extension at PATH by NAME,
+    /// which defaults to the file name of PATH without its extension
+    #[clap(long = "extension", name = "[NAME=]PATH", env = "ECHOLITE_EXTENSIONS", value_delimiter = ',', value_parser = to_extension)]
+    pub extensions: Vec<Extension>,
+
+    /// Set the highest value of an SQLite limit, such as `length` or `sql_length`,
+    /// which sessions start at and clients may only lower
+    #[clap(long = "limit", name = "NAME=VALUE", env = "ECHOLITE_LIMITS", value_delimiter = ',', value_parser = to_ceiling)]
+    pub limits: Vec<Ceiling>,
+
+    /// Pin an SQLite database option, such as `defensive` or `trusted_schema`, on or off
+    /// for all sessions, so that clients cannot change it, with `PRAGMA foreign_keys`
+    /// or `PRAGMA trusted_schema` neither
+    #[clap(long = "db-config", name = "NAME=on|off", env = "ECHOLITE_DB_CONFIG", value_delimiter = ',', value_parser = to_pin)]
+    pub pins: Vec<Pin>,
+
+    /// Set the PEM certificate chain to serve TLS with
+    #[clap(long, env = "ECHOLITE_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Set the PEM private key of the TLS certificate
+    #[clap(long, env = "ECHOLITE_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Require client certificates issued by this PEM CA bundle
+    #[clap(long, env = "ECHOLITE_TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Set the client certificate field logged as the user identity
+    #[clap(
+        long,
+        env = "ECHOLITE_TLS_CLIENT_IDENTITY",
+        value_enum,
+        default_value_t = ClientIdentity::Cn
+    )]
+    pub tls_client_identity: ClientIdentity,
+
+    /// Skip the password step for clients presenting a trusted certificate
+    #[clap(long, env = "ECHOLITE_TLS_SKIP_PASSWORD", requires = "tls_client_ca")]
+    pub tls_skip_password: bool,
+
+    /// Set the memory cost in KiB of the Argon2 hash answering the login challenge
+    #[clap(long, env = "ECHOLITE_ARGON2_M_COST", default_value = "65536")]
+    pub argon2_m_cost: u32,
+
+    /// Set the number of iterations of the Argon2 hash answering the login challenge
+    #[clap(long, env = "ECHOLITE_ARGON2_T_COST", default_value = "8")]
+    pub argon2_t_cost: u32,
+
+    /// Set the degree of parallelism of the Argon2 hash answering the login challenge
+    #[clap(long, env = "ECHOLITE_ARGON2_P_COST", default_value = "1")]
+    pub argon2_p_cost: u32,
+
+    /// Set the number of logins verified at once, each taking the Argon2 memory cost
+    #[clap(long, env = "ECHOLITE_ARGON2_CONCURRENCY", default_value = "2")]
+    pub argon2_concurrency: NonZeroUsize,
+
+    /// Set the number of rows sent per batch of a query result
+    #[clap(long, env = "ECHOLITE_BATCH_SIZE", default_value = "1000")]
+    pub batch_size: NonZeroUsize,
+
+    /// Set the maximum number of prepared statements a session may hold
+    #[clap(long, env = "ECHOLITE_MAX_PREPARED", default_value = "128")]
+    pub max_prepared: usize,
+
+    /// Set the maximum number of cursors a session may hold open
+    #[clap(long, env = "ECHOLITE_MAX_CURSORS", default_value = "16")]
+    pub max_cursors: usize,
+
+    /// Set the maximum number of rows returned by all the statements of a script
+    #[clap(long, env = "ECHOLITE_MAX_SCRIPT_ROWS", default_value = "100000")]
+    pub max_script_rows: usize,
+
+    /// Set the time limit of a command running SQL in milliseconds, also the most a client
+    /// may ask for with a query, execute or transaction command
+    #[clap(long, env = "ECHOLITE_TIMEOUT")]
+    pub timeout: Option<u64>,
+
+    /// Set the maximum length in bytes of a string or BLOB received from clients
+    #[clap(long, env = "ECHOLITE_MAX_STRING_LENGTH", default_value_t = DecodeLimits::DEFAULT.max_string_length)]
+    pub max_string_length: u64,
+
+    /// Set the maximum length in bytes of the SQL of a command
+    #[clap(long, env = "ECHOLITE_MAX_SQL_LENGTH", default_value_t = DecodeLimits::DEFAULT.max_sql_length)]
+    pub max_sql_length: u64,
+
+    /// Set the maximum number of statements of a transaction command
+    #[clap(long, env = "ECHOLITE_MAX_STATEMENTS", default_value_t = DecodeLimits::DEFAULT.max_statements)]
+    pub max_statements: u64,
+
+    /// Set the maximum number of parameters bound to a statement
+    #[clap(long, env = "ECHOLITE_MAX_VALUES", default_value_t = DecodeLimits::DEFAULT.max_values)]
+    pub max_values: u64,
 
     /// Set log level
     #[clap(
@@ -29,10 +166,183 @@ pub struct Args {
     pub log: LevelFilter,
 }
 
+/// Restrictions on the flags and paths clients open databases with
+#[derive(clap::Args, Debug, Clone, Copy, Default)]
+pub struct OpenPolicy {
+    /// Open all databases read-only
+    #[clap(long, env = "ECHOLITE_READ_ONLY")]
+    pub read_only: bool,
+
+    /// Forbid creating database files that don't exist
+    #[clap(long, env = "ECHOLITE_NO_CREATE")]
+    pub no_create: bool,
+
+    /// Forbid URI filenames
+    #[clap(long, env = "ECHOLITE_NO_URI")]
+    pub no_uri: bool,
+
+    /// Forbid in-memory databases
+    #[clap(long, env = "ECHOLITE_NO_MEMORY")]
+    pub no_memory: bool,
+}
+
+impl OpenPolicy {
+    /// The flags to open `path` with, given the ones asked for by the client
+    pub fn apply(&self, path: &str, mut flags: Flags) -> Result<Flags, Error> {
+        if self.read_only {
+            flags.set(SQLITE_OPEN_READWRITE, false);
+            flags.set(SQLITE_OPEN_CREATE, false);
+            flags.set(SQLITE_OPEN_READONLY, true);
+        }
+        if self.no_create {
+            flags.set(SQLITE_OPEN_CREATE, false);
+        }
+        let uri = path.starts_with("file:");
+        if self.no_uri {
+            // Refused rather than masked, as SQLite may be built to take URIs regardless
+            if uri {
+                return Err(Error::OpenPolicy("URI filenames are not allowed"));
+            }
+            flags.set(SQLITE_OPEN_URI, false);
+        }
+        if self.no_memory {
+            let memory = path == ":memory:"
+                || flags.contains(SQLITE_OPEN_MEMORY)
+                || (uri && (path.starts_with("file::memory:") || path.contains("mode=memory")));
+            if memory {
+                return Err(Error::OpenPolicy("in-memory databases are not allowed"));
+            }
+        }
+        Ok(flags)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Subcommands {
+    /// Read a password from standard input and print its hash, to store in a `--password-file`
+    /// or to list in a users file. The hash is enough to log in, keep it as secret as the password
+    HashPassword {
+        /// Set the memory cost in KiB of the hash
+        #[clap(long, default_value_t = Params::default().m_cost)]
+        m_cost: u32,
+
+        /// Set the number of iterations of the hash
+        #[clap(long, default_value_t = Params::default().t_cost)]
+        t_cost: u32,
+
+        /// Set the degree of parallelism of the hash
+        #[clap(long, default_value_t = Params::default().p_cost)]
+        p_cost: u32,
+    },
+}
+
+impl Args {
+    /// The single password of the server, if not using a users file
+    pub fn password(&self) -> Option<&Password> {
+        self.password
+            .as_ref()
+            .or(self.password_file.as_ref())
+    }
+
+    /// Path of the extension a client may load by `name`, its name or its path
+    pub fn extension(&self, name: &str) -> Option<&Path> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.name == name || extension.path == Path::new(name))
+            .map(|extension| extension.path.as_path())
+    }
+
+    /// Value of `limit` a client may set, given the one asked for
+    pub fn limit(&self, limit: Limit, requested: u64) -> u64 {
+        self.limits
+            .iter()
+            .filter(|ceiling| ceiling.limit == limit)
+            .fold(requested, |value, ceiling| value.min(ceiling.value))
+    }
+
+    /// Whether `option` is pinned on or off
+    pub fn pinned(&self, option: DbConfig) -> Option<bool> {
+        self.pins
+            .iter()
+            .rfind(|pin| pin.option == option)
+            .map(|pin| pin.enabled)
+    }
+
+    /// Argon2 params of the login challenge
+    pub fn params(&self) -> Params {
+        Params {
+            m_cost: self.argon2_m_cost,
+            t_cost: self.argon2_t_cost,
+            p_cost: self.argon2_p_cost,
+        }
+    }
+
+    /// Limits of the messages received from clients
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_string_length: self.max_string_length,
+            max_sql_length: self.max_sql_length,
+            max_statements: self.max_statements,
+            max_values: self.max_values,
+        }
+    }
+
+    /// Time limit of a command, given the one asked for by the client if any
+    pub fn timeout(&self, requested: Option<u64>) -> Option<Duration> {
+        let timeout = match (self.timeout, requested) {
+            (Some(max), Some(requested)) => Some(max.min(requested)),
+            (max, requested) => max.or(requested),
+        };
+        timeout.map(Duration::from_millis)
+    }
+}
+
+/// Client certificate field naming the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ClientIdentity {
+    /// Subject common name
+    Cn,
+    /// First DNS, email or URI subject alternative name
+    San,
+}
+
 const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 const PORT: u16 = 4567;
 const DEFAULT_BIND: SocketAddr = SocketAddr::new(IP, PORT);
 
+/// Address to listen on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn to_bind(s: &str) -> Result<Bind, String> {
+    // unix:/run/echolite.sock
+    if let Some(path) = s.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err("Unix domain socket path is empty".to_string());
+        }
+        #[cfg(unix)]
+        return Ok(Bind::Unix(path.into()));
+        #[cfg(not(unix))]
+        return Err("Unix domain sockets are not supported on this platform".to_string());
+    }
+    to_socket_addr(s).map(Bind::Tcp)
+}
+
 fn to_socket_addr(s: &str) -> Result<SocketAddr, String> {
     // 0.0.0.0:80
     if let Ok(addr) = s.parse::<SocketAddr>() {
@@ -49,21 +359,158 @@ fn to_socket_addr(s: &str) -> Result<SocketAddr, String> {
     Err(format!("Cannot parse `{}` to SocketAddr", s))
 }
 
-static LIMIT: Semaphore = Semaphore::const_new(2);
+fn to_root(s: &str) -> Result<PathBuf, String> {
+    let root = Path::new(s)
+        .canonicalize()
+        .map_err(|e| format!("Cannot resolve `{}`: {}", s, e))?;
+    match root.is_dir() {
+        true => Ok(root),
+        false => Err(format!("`{}` is not a directory", s)),
+    }
+}
+
+/// An extension clients are allowed to load
+#[derive(Debug, Clone)]
+pub struct Extension {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+fn to_extension(s: &str) -> Result<Extension, String> {
+    let (name, path) = match s.split_once('=') {
+        Some((name, path)) => (name.to_string(), PathBuf::from(path)),
+        None => {
+            let path = PathBuf::from(s);
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| format!("Cannot name extension `{}`", s))?
+                .to_string();
+            (name, path)
+        }
+    };
+    if name.is_empty() || path.as_os_str().is_empty() {
+        return Err(format!("Cannot parse `{}` to extension", s));
+    }
+    Ok(Extension { name, path })
+}
+
+/// The highest value of a limit clients may set
+#[derive(Debug, Clone, Copy)]
+pub struct Ceiling {
+    pub limit: Limit,
+    pub value: u64,
+}
+
+fn to_ceiling(s: &str) -> Result<Ceiling, String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Cannot parse `{}` to limit, expected NAME=VALUE", s))?;
+    let limit = Limit::ALL
+        .into_iter()
+        .find(|limit| limit.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Unknown limit `{}`", name))?;
+    let value = value
+        .parse()
+        .map_err(|e| format!("Cannot parse limit `{}`: {}", name, e))?;
+    Ok(Ceiling { limit, value })
+}
+
+/// A database option pinned on or off
+#[derive(Debug, Clone, Copy)]
+pub struct Pin {
+    pub option: DbConfig,
+    pub enabled: bool,
+}
+
+fn to_pin(s: &str) -> Result<Pin, String> {
+    let (name, enabled) = s.split_once('=').ok_or_else(|| {
+        format!(
+            "Cannot parse `{}` to database option, expected NAME=on|off",
+            s
+        )
+    })?;
+    let option = DbConfig::ALL
+        .into_iter()
+        .find(|option| option.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Unknown database option `{}`", name))?;
+    let enabled = match enabled {
+        "on" | "true" | "1" => true,
+        "off" | "false" | "0" => false,
+        _ => return Err(format!("Cannot parse `{}` to on or off", enabled)),
+    };
+    Ok(Pin { option, enabled })
+}
+
+#[cfg(unix)]
+fn to_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("Cannot parse `{}` to octal permissions", s)),
+    }
+}
+
+/// Logins verified at once, permits being added by [`set_concurrency`] at startup
+static LIMIT: Semaphore = Semaphore::const_new(0);
+
+pub fn set_concurrency(concurrency: NonZeroUsize) {
+    LIMIT.add_permits(concurrency.get());
+}
+
+/// Checks that Argon2 accepts `params`, returning whether they meet the OWASP minimums
+pub fn check_params(params: Params) -> Result<bool, argon2::Error> {
+    argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, None)?;
+    Ok(params.meets_owasp())
+}
 
 #[derive(Debug, Clone)]
-pub struct Password(Arc<SecurePassword>);
+pub enum Password {
+    Plain(Arc<SecurePassword>),
+    Hash(Arc<Verifier>),
+}
 
 #[derive(Debug, Zeroize, ZeroizeOnDrop)]
-struct SecurePassword(String);
+pub struct SecurePassword(String);
 
 impl Password {
     fn from_str(value: &str) -> Result<Self, String> {
-        Ok(Password(Arc::new(SecurePassword(value.to_string()))))
+        Ok(Password::Plain(Arc::new(SecurePassword(value.to_string()))))
+    }
+
+    fn from_hash(value: &str) -> Result<Self, String> {
+        let verifier =
+            Verifier::parse(value).map_err(|e| format!("Invalid password hash: {}", e))?;
+        Ok(Password::Hash(Arc::new(verifier)))
+    }
+
+    /// A file holding a password hash, recognized by its `$argon2` prefix, or a password.
+    /// A trailing line break is not part of the password.
+    fn from_file(path: &str) -> Result<Self, String> {
+        let mut text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read password file `{}`: {}", path, e))?;
+        let len = text.trim_end_matches(['\r', '\n']).len();
+        text.truncate(len);
+        let password = match text.starts_with("$argon2") {
+            true => Self::from_hash(&text),
+            false => Self::from_str(&text),
+        };
+        text.zeroize();
+        password
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.0.is_empty()
+        match self {
+            Password::Plain(password) => password.0.is_empty(),
+            Password::Hash(_) => false,
+        }
+    }
+
+    /// Salt and params the client hashes the password with before the challenge, if any
+    pub fn prehash(&self) -> Option<&Prehash> {
+        match self {
+            Password::Plain(_) => None,
+            Password::Hash(verifier) => Some(verifier.prehash()),
+        }
     }
 
     pub async fn verify(
@@ -71,11 +518,64 @@ impl Password {
         client_salt: Salt,
         server_salt: Salt,
         params: Params,
+        mutual: bool,
         client_password: [u8; 32],
-    ) -> Result<bool, Error> {
-        let _limit = LIMIT.acquire().await?;
-        let server_password =
-            to_hash_password(self.0.0.as_str(), client_salt, server_salt, params).await?;
-        Ok(server_password == client_password)
+    ) -> Result<Verified, Error> {
+        match self {
+            Password::Plain(password) => {
+                let secret = password.0.as_bytes();
+                verify(
+                    secret,
+                    client_salt,
+                    server_salt,
+                    params,
+                    mutual,
+                    client_password,
+                )
+                .await
+            }
+            Password::Hash(verifier) => {
+                verifier
+                    .verify(client_salt, server_salt, params, mutual, client_password)
+                    .await
+            }
+        }
+    }
+}
+
+/// Outcome of checking the client's answer to the login challenge
+#[derive(Debug)]
+pub enum Verified {
+    Failed,
+    /// The answer is right, with the proof to send back in a mutual session
+    Passed(Option<ServerProof>),
+}
+
+/// Checks the client's answer to the challenge for `secret`,
+/// in a mutual session also deriving the proof of the server
+pub async fn verify(
+    secret: &[u8],
+    client_salt: Salt,
+    server_salt: Salt,
+    params: Params,
+    mutual: bool,
+    client_password: [u8; 32],
+) -> Result<Verified, Error> {
+    let _limit = LIMIT.acquire().await?;
+    let (server_password, proof) = match mutual {
+        true => {
+            let (server_password, proof) =
+                to_mutual_hash_password(secret, client_salt, server_salt, params).await?;
+            (server_password, Some(proof))
+        }
+        false => {
+            let server_password =
+                to_hash_password(secret, client_salt, server_salt, params).await?;
+            (server_password, None)
+        }
+    };
+    match server_password == client_password {
+        true => Ok(Verified::Passed(proof)),
+        false => Ok(Verified::Failed),
     }
 }
@@ -0,0 +1,43 @@
+use protocol::CancelKey;
+use rusqlite::InterruptHandle;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Interrupt handles of the open sessions by their cancel key
+#[derive(Default)]
+pub struct Interrupts {
+    handles: Mutex<HashMap<CancelKey, InterruptHandle>>,
+}
+
+impl Interrupts {
+    /// Registers the handle of a session until the returned guard is dropped
+    pub fn register(self: &Arc<Self>, key: CancelKey, handle: InterruptHandle) -> Registration {
+        self.handles.lock().unwrap().insert(key, handle);
+        Registration {
+            interrupts: self.clone(),
+            key,
+        }
+    }
+
+    /// Interrupts the session holding `key`, returns whether there is one
+    pub fn interrupt(&self, key: &CancelKey) -> bool {
+        match self.handles.lock().unwrap().get(key) {
+            Some(handle) => {
+                handle.interrupt();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct Registration {
+    interrupts: Arc<Interrupts>,
+    key: CancelKey,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.interrupts.handles.lock().unwrap().remove(&self.key);
+    }
+}
@@ -1,22 +1,41 @@
 mod cli;
+mod interrupt;
+mod sandbox;
 mod sqlite;
+mod tls;
+#[cfg(unix)]
+mod unix;
+mod users;
+mod verifier;
+mod worker;
 
-use crate::cli::Password;
+use crate::cli::{Args, Bind, Subcommands, Verified};
+use crate::interrupt::Interrupts;
 use clap::Parser;
-use protocol::*;
-use sqlite::Sqlite;
+use protocol::{consts::*, *};
+use rusqlite::ErrorCode;
 use std::io::Error as IoError;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::BufStream;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tokio::net::unix::UCred;
 use tokio::time::sleep;
+use tokio_rustls::TlsAcceptor;
 use tracing::level_filters::LevelFilter;
-use tracing::{error, info, trace, warn};
+use tracing::{Span, error, info, trace, warn};
 use tracing_subscriber::filter::Targets;
 use tracing_subscriber::fmt;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use worker::Worker;
+use zeroize::Zeroize;
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -26,17 +45,110 @@ pub enum Error {
     Io(#[from] IoError),
     #[error("Protocol Error: {0}")]
     Protocol(#[from] protocol::Error),
+    #[error("TLS Error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    #[error("TLS Client Verifier Error: {0}")]
+    TlsVerifier(#[from] tokio_rustls::rustls::server::VerifierBuilderError),
+    #[error("Invalid PEM File {0}: {1}")]
+    Pem(PathBuf, tokio_rustls::rustls::pki_types::pem::Error),
+    #[cfg(unix)]
+    #[error("Not A Socket File: {0}")]
+    NotSocket(PathBuf),
+    #[cfg(unix)]
+    #[error("Socket Already In Use: {0}")]
+    SocketInUse(PathBuf),
     #[error("SQLite: {0}")]
     Sqlite(#[from] rusqlite::Error),
     #[error("SQLite: Invalid Flags For File Open Operations")]
     InvalidFlags,
+    #[error("Open Refused By Policy: {0}")]
+    OpenPolicy(&'static str),
+    #[error("Database Option Pinned: {0}")]
+    DbConfigPinned(DbConfig),
+    #[error("Extension Not Allowed: {0}")]
+    ExtensionNotAllowed(String),
+    #[error("Parent Directory In Database Path: {0}")]
+    ParentPath(String),
+    #[error("Database Path Escapes Root Directory: {0}")]
+    PathEscapesRoot(String),
+    #[error("Database Path Not Allowed: {0}")]
+    PathNotAllowed(String),
+    #[error("Unknown Prepared Statement: {0}")]
+    UnknownStatement(u64),
+    #[error("Too Many Prepared Statements: {0}")]
+    TooManyStatements(usize),
+    #[error("Unknown Cursor: {0}")]
+    UnknownCursor(u64),
+    #[error("Too Many Open Cursors: {0}")]
+    TooManyCursors(usize),
+    #[error("Too Many Rows: a script may return at most {0} rows")]
+    TooManyRows(usize),
+    #[error("Command Timed Out After {0}ms")]
+    Timeout(u64),
+    #[error("Session Worker Stopped")]
+    Worker,
     #[error("Tokio Semaphore Acquire Error: {0}")]
     Semaphore(#[from] tokio::sync::AcquireError),
 }
 
+impl Error {
+    /// The error status to respond with
+    pub fn to_status(&self) -> ErrorStatus {
+        let class = match self {
+            Error::Sqlite(e) if e.sqlite_error_code() == Some(ErrorCode::OperationInterrupted) => {
+                ErrorClass::Interrupted
+            }
+            Error::Sqlite(_) => ErrorClass::Sqlite,
+            Error::TooManyCursors(_)
+            | Error::TooManyStatements(_)
+            | Error::TooManyRows(_)
+            | Error::ParentPath(_)
+            | Error::PathEscapesRoot(_)
+            | Error::PathNotAllowed(_)
+            | Error::OpenPolicy(_)
+            | Error::ExtensionNotAllowed(_)
+            | Error::DbConfigPinned(_) => ErrorClass::Policy,
+            Error::Timeout(_) => ErrorClass::Timeout,
+            _ => ErrorClass::Protocol,
+        };
+        let mut status = ErrorStatus::new(class, self.to_string());
+        match self {
+            Error::Sqlite(rusqlite::Error::SqliteFailure(error, _)) => {
+                status.code = error.extended_code & 0xff;
+                status.extended_code = error.extended_code;
+            }
+            #[cfg(feature = "bundled")]
+            Error::Sqlite(rusqlite::Error::SqlInputError {
+                error, msg, offset, ..
+            }) => {
+                status.code = error.extended_code & 0xff;
+                status.extended_code = error.extended_code;
+                status.offset = Some(*offset as u64);
+                status.message = format!("SQLite: {msg}");
+            }
+            _ => {}
+        }
+        status
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let args = cli::Args::parse();
+    let args = Args::parse();
+    if let Some(Subcommands::HashPassword {
+        m_cost,
+        t_cost,
+        p_cost,
+    }) = args.command
+    {
+        hash_password(Params {
+            m_cost,
+            t_cost,
+            p_cost,
+        })
+        .await;
+        return;
+    }
 
     tracing_subscriber::registry()
         .with(fmt::layer().with_target(false))
@@ -47,50 +159,213 @@ async fn main() {
         )
         .init();
 
-    if args.password.is_empty() {
+    let params = args.params();
+    match cli::check_params(params) {
+        Ok(true) => {}
+        Ok(false) => warn!(
+            ?params,
+            "Argon2 params below the OWASP recommended minimums"
+        ),
+        Err(err) => {
+            error!("Invalid Argon2 params {:?}: {}", params, err);
+            std::process::exit(1);
+        }
+    }
+    cli::set_concurrency(args.argon2_concurrency);
+
+    if args.password().is_some_and(|p| p.is_empty()) && !args.tls_skip_password {
         warn!("Authorization password is not set!!!");
     }
-    if !args.bind.ip().is_loopback() {
-        warn!("Binding to non-loopback address!!!");
+    if let Bind::Tcp(addr) = args.bind
+        && !addr.ip().is_loopback()
+        && args.tls_cert.is_none()
+    {
+        warn!("Binding to non-loopback address without TLS!!!");
     }
 
-    let listener = TcpListener::bind(args.bind).await.unwrap_or_else(|err| {
-        error!("Failed to bind to {}: {}", args.bind, err);
-        std::process::exit(1);
-    });
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let client_ca = args.tls_client_ca.as_deref();
+            let acceptor = tls::acceptor(cert, key, client_ca).unwrap_or_else(|err| {
+                error!("Failed to load TLS certificate: {}", err);
+                std::process::exit(1);
+            });
+            match client_ca {
+                Some(_) => info!("TLS enabled, client certificates required"),
+                None => info!("TLS enabled"),
+            }
+            Some(acceptor)
+        }
+        _ => None,
+    };
 
-    let addr = listener.local_addr().unwrap_or_else(|err| {
-        error!("Failed to get local address: {}", err);
-        std::process::exit(1);
-    });
-    info!("Listening on: {}", addr);
+    let listener = match &args.bind {
+        Bind::Tcp(addr) => {
+            let tcp = TcpListener::bind(addr).await.unwrap_or_else(|err| {
+                error!("Failed to bind to {}: {}", addr, err);
+                std::process::exit(1);
+            });
+            let addr = tcp.local_addr().unwrap_or_else(|err| {
+                error!("Failed to get local address: {}", err);
+                std::process::exit(1);
+            });
+            info!("Listening on: {}", addr);
+            Listener::Tcp(tcp)
+        }
+        #[cfg(unix)]
+        Bind::Unix(path) => {
+            let unix = unix::bind(path, args.socket_mode).unwrap_or_else(|err| {
+                error!("Failed to bind to {}: {}", args.bind, err);
+                std::process::exit(1);
+            });
+            info!("Listening on: {}", args.bind);
+            Listener::Unix(unix)
+        }
+    };
 
-    if let Err(err) = run(listener, args.password).await {
+    if let Err(err) = run(listener, Arc::new(args), Arc::default(), tls).await {
         error!("Error : {:?}", err);
         std::process::exit(1);
     }
 }
 
-async fn run(tcp: TcpListener, password: Password) -> Result<()> {
+/// Prints the hash of the password read from standard input, for `--password-file`
+async fn hash_password(params: Params) {
+    match cli::check_params(params) {
+        Ok(true) => {}
+        Ok(false) => eprintln!("Warning: Argon2 params below the OWASP recommended minimums"),
+        Err(err) => {
+            eprintln!("Invalid Argon2 params {:?}: {}", params, err);
+            std::process::exit(1);
+        }
+    }
+    let mut password = String::new();
+    if let Err(err) = std::io::stdin().read_line(&mut password) {
+        eprintln!("Failed to read password: {}", err);
+        std::process::exit(1);
+    }
+    let len = password.trim_end_matches(['\r', '\n']).len();
+    password.truncate(len);
+    if password.is_empty() {
+        eprintln!("Password is empty");
+        std::process::exit(1);
+    }
+    let hash = verifier::hash_password(&password, params).await;
+    password.zeroize();
+    match hash {
+        Ok(hash) => println!("{}", hash),
+        Err(err) => {
+            eprintln!("Failed to hash password: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// The other end of an accepted connection
+enum Peer {
+    Tcp(SocketAddr),
+    /// Credentials of the connecting process, when the platform provides them
+    #[cfg(unix)]
+    Unix(Option<UCred>),
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Peer::Unix(Some(cred)) => match cred.pid() {
+                Some(pid) => write!(f, "unix:uid={},pid={}", cred.uid(), pid),
+                None => write!(f, "unix:uid={}", cred.uid()),
+            },
+            #[cfg(unix)]
+            Peer::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+async fn run(
+    listener: Listener,
+    args: Arc<Args>,
+    interrupts: Arc<Interrupts>,
+    tls: Option<TlsAcceptor>,
+) -> Result<()> {
     loop {
-        let (stream, client) = match tcp.accept().await {
-            Ok(v) => v,
-            Err(e) => {
-                error!("Failed to accept TcpStream: {}", e);
-                sleep(Duration::from_secs(3)).await;
-                continue;
-            }
+        let accepted = match &listener {
+            Listener::Tcp(tcp) => tcp.accept().await.map(|(stream, client)| {
+                tokio::spawn(connection(
+                    stream,
+                    Peer::Tcp(client),
+                    args.clone(),
+                    interrupts.clone(),
+                    tls.clone(),
+                ));
+            }),
+            #[cfg(unix)]
+            Listener::Unix(unix) => unix.accept().await.map(|(stream, _)| {
+                let client = Peer::Unix(stream.peer_cred().ok());
+                tokio::spawn(connection(
+                    stream,
+                    client,
+                    args.clone(),
+                    interrupts.clone(),
+                    tls.clone(),
+                ));
+            }),
         };
-        tokio::spawn(connection(stream, client, password.clone()));
+        if let Err(e) = accepted {
+            error!("Failed to accept connection: {}", e);
+            sleep(Duration::from_secs(3)).await;
+        }
     }
 }
 
-#[tracing::instrument(skip(stream, password))]
-async fn connection(stream: TcpStream, client: SocketAddr, password: Password) {
-    trace!("Accepted TcpStream successfully");
-    let stream = BufStream::new(stream);
+#[tracing::instrument(skip(stream, client, args, interrupts, tls), fields(client = %client, identity, user))]
+async fn connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    client: Peer,
+    args: Arc<Args>,
+    interrupts: Arc<Interrupts>,
+    tls: Option<TlsAcceptor>,
+) {
+    trace!("Accepted connection successfully");
     info!("Start handling connection");
-    match handler(stream, password).await {
+    let handled = match tls {
+        Some(tls) => match tls.accept(stream).await {
+            Ok(stream) => {
+                let certified = stream.get_ref().1.peer_certificates();
+                let identity = certified.and_then(|certs| {
+                    info!("Client certificate verified");
+                    let identity = tls::identity(certs, args.tls_client_identity);
+                    match &identity {
+                        Some(identity) => {
+                            Span::current().record("identity", identity);
+                        }
+                        None => warn!("Client certificate names no identity"),
+                    }
+                    identity
+                });
+                let certified = certified.is_some();
+                handler(
+                    BufStream::new(stream),
+                    args,
+                    interrupts,
+                    certified,
+                    identity,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        },
+        None => handler(BufStream::new(stream), args, interrupts, false, None).await,
+    };
+    match handled {
         Ok(_) => {
             info!("Connection handling finished");
         }
@@ -100,89 +375,628 @@ async fn connection(stream: TcpStream, client: SocketAddr, password: Password) {
     };
 }
 
-async fn handler(mut stream: BufStream<TcpStream>, password: Password) -> Result<()> {
+async fn handler<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: BufStream<S>,
+    args: Arc<Args>,
+    interrupts: Arc<Interrupts>,
+    certified: bool,
+    identity: Option<String>,
+) -> Result<()> {
     write_protocol_version(&mut stream).await?;
 
-    let client_salt = read_salt(&mut stream).await?;
-    let server_salt = rand_salt();
-    write_salt(&mut stream, server_salt).await?;
+    let mutual = match read_request(&mut stream).await? {
+        Request::Session => false,
+        Request::MutualSession => true,
+        Request::Cancel(key) => {
+            match interrupts.interrupt(&key) {
+                true => info!("Cancel request interrupted a session"),
+                false => warn!("Cancel request for no session"),
+            }
+            // Same response either way, so cancel keys can't be probed for
+            write_status(&mut stream, Status::Ok).await?;
+            return Ok(());
+        }
+    };
+
+    // With a users file, a trusted certificate stands for the user its identity names
+    let mut user = None;
+    let method = match (certified && args.tls_skip_password, &args.users) {
+        (true, None) => AuthMethod::Certificate,
+        (true, Some(users)) => match identity.and_then(|identity| users.get(&identity)) {
+            Some(certified) => {
+                user = Some(certified);
+                AuthMethod::Certificate
+            }
+            None => AuthMethod::Password,
+        },
+        (false, _) => AuthMethod::Password,
+    };
+    write_auth_method(&mut stream, method).await?;
 
-    let params = Params::default();
-    write_hash_params(&mut stream, params).await?;
+    if method == AuthMethod::Password {
+        let name = read_user(&mut stream).await?;
+        let client_salt = read_salt(&mut stream).await?;
+        let server_salt = rand_salt();
+        write_salt(&mut stream, server_salt).await?;
 
-    let hashed = read_auth_password(&mut stream).await?;
-    match password
-        .verify(client_salt, server_salt, params, hashed)
-        .await?
-    {
-        true => {
-            write_status(&mut stream, Status::Ok).await?;
+        let params = args.params();
+        write_hash_params(&mut stream, params).await?;
+
+        let verified = match (&args.users, args.password()) {
+            (Some(users), _) => {
+                let found = users.get(&name);
+                let prehash = match &found {
+                    Some(found) => found.prehash().clone(),
+                    None => users.decoy(&name),
+                };
+                write_prehash(&mut stream, Some(&prehash)).await?;
+                let hashed = read_auth_password(&mut stream).await?;
+                match found {
+                    Some(found) => {
+                        let verified = found
+                            .verify(client_salt, server_salt, params, mutual, hashed)
+                            .await?;
+                        user = Some(found);
+                        verified
+                    }
+                    None => {
+                        // Take as long as for a known user
+                        cli::verify(&[0; 32], client_salt, server_salt, params, mutual, hashed)
+                            .await?;
+                        Verified::Failed
+                    }
+                }
+            }
+            (None, Some(password)) => {
+                write_prehash(&mut stream, password.prehash()).await?;
+                let hashed = read_auth_password(&mut stream).await?;
+                password
+                    .verify(client_salt, server_salt, params, mutual, hashed)
+                    .await?
+            }
+            (None, None) => unreachable!("clap requires a password or a users file"),
+        };
+        match verified {
+            Verified::Passed(proof) => {
+                write_status(&mut stream, Status::Ok).await?;
+                if let Some(proof) = proof {
+                    write_server_proof(&mut stream, proof).await?;
+                }
+            }
+            Verified::Failed => {
+                error!(%name, "Password verification failed");
+                let error = ErrorStatus::new(ErrorClass::Auth, "Password verification failed");
+                write_status(&mut stream, Status::Err(error)).await?;
+                return Ok(());
+            }
         }
-        false => {
-            error!("Password verification failed");
-            write_status(
-                &mut stream,
-                Status::Err("Password verification failed".into()),
-            )
-            .await?;
+    }
+    if let Some(user) = &user {
+        Span::current().record("user", &user.name);
+        info!(role = ?user.role, "Authenticated user");
+    }
+
+    let (mut path, mut flags) = read_connect(&mut stream).await?;
+    if let Some(user) = &user {
+        if !user.allows(&path) {
+            error!(%path, "Database not allowed for user");
+            let message = format!("User `{}` may not open `{}`", user.name, path);
+            let error = ErrorStatus::new(ErrorClass::Policy, message);
+            write_status(&mut stream, Status::Err(error)).await?;
             return Ok(());
         }
+        flags = user.flags(flags);
     }
-
-    let (path, flags) = read_connect(&mut stream).await?;
-    let mut conn = match Sqlite::connect(&path, flags) {
-        Ok(conn) => {
+    if let Some(root) = &args.root {
+        match sandbox::resolve(root, &args.allow, &path) {
+            Ok(resolved) => {
+                trace!(%path, %resolved, "Resolved database path");
+                // Refuse a symlink swapped in after resolving
+                flags.set(SQLITE_OPEN_NOFOLLOW, true);
+                path = resolved;
+            }
+            Err(error) => {
+                error!(%path, %error, "Database path refused");
+                write_status(&mut stream, Status::Err(error.to_status())).await?;
+                return Ok(());
+            }
+        }
+    }
+    // Attaching would get around the checks of the path above
+    let confined = args.root.is_some() || user.as_ref().is_some_and(|user| !user.unconfined());
+    let key = rand_cancel_key();
+    let (mut conn, _registration) = match Worker::connect(
+        path.clone(),
+        flags,
+        args.open_policy,
+        args.limits.clone(),
+        args.pins.clone(),
+        confined,
+    )
+    .await
+    {
+        Ok((conn, handle)) => {
             info!(%flags, %path, "Connected to database successfully");
+            let registration = interrupts.register(key, handle);
             write_status(&mut stream, Status::Ok).await?;
-            conn
+            write_cancel_key(&mut stream, key).await?;
+            (conn, registration)
         }
         Err(error) => {
             error!(%flags, %path, %error, "Failed to connect to database");
-            write_status(&mut stream, Status::Err(error.to_string())).await?;
+            write_status(&mut stream, Status::Err(error.to_status())).await?;
             return Ok(());
         }
     };
 
+    let limits = args.decode_limits();
     loop {
-        let command = read_command(&mut stream).await?;
+        let command = match read_command(&mut stream, &limits).await {
+            Ok(command) => command,
+            Err(error @ protocol::Error::TooLarge(..)) => {
+                // The rest of the command is left unread, so the session can't go on
+                warn!(%error, "Closing connection");
+                let error = Error::from(error);
+                write_response(
+                    &mut stream,
+                    Status::Err(error.to_status()),
+                    conn.transaction_state(),
+                )
+                .await?;
+                stream.shutdown().await?;
+                break;
+            }
+            Err(error) => return Err(error.into()),
+        };
         trace!(?command, "Received");
         match command {
             Command::Ping => {
-                write_status(&mut stream, Status::Ok).await?;
+                write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
             }
             Command::Disconnect => {
                 break;
             }
-            Command::SimpleExecute { sql } => {
-                match conn.execute(&sql) {
-                    Ok(_) => {
-                        write_status(&mut stream, Status::Ok).await?;
+            Command::SimpleExecute {
+                sql,
+                params,
+                timeout,
+            } => {
+                let timeout = args.timeout(timeout);
+                let executed = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    let executed = conn.execute(&sql, &params).map_err(|e| conn.timed_out(e));
+                    conn.clear_timeout()?;
+                    executed
+                });
+                match executed.await? {
+                    Ok(changes) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_changes(&mut stream, changes).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                };
+            }
+            Command::SimpleQuery {
+                sql,
+                params,
+                timeout,
+            } => {
+                // The time limit covers streaming the rows too
+                let timeout = args.timeout(timeout);
+                let opened = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    conn.open(&sql, &params).map_err(|e| conn.timed_out(e))
+                });
+                match opened.await? {
+                    Ok((cursor, columns)) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_columns(&mut stream, &columns).await?;
+                        stream_rows(&mut stream, &mut conn, cursor, args.batch_size).await?;
                     }
                     Err(e) => {
-                        write_status(&mut stream, Status::Err(e.to_string())).await?;
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
                     }
                 };
+                conn.run(|conn| conn.clear_timeout()).await??;
+            }
+            Command::Transaction { sqls, timeout } => {
+                let timeout = args.timeout(timeout);
+                let committed = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    let committed = conn.transaction(sqls).map_err(|e| conn.timed_out(e));
+                    conn.clear_timeout()?;
+                    committed
+                });
+                match committed.await? {
+                    Ok(changes) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_transaction(&mut stream, changes).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
             }
-            Command::SimpleQuery { sql } => {
-                match conn.query(&sql) {
-                    Ok(query) => {
-                        write_status(&mut stream, Status::Ok).await?;
-                        write_query(&mut stream, query).await?;
+            Command::Prepare { sql } => {
+                let max_prepared = args.max_prepared;
+                let prepared = conn.run(move |conn| match conn.statements() < max_prepared {
+                    true => conn.prepare(&sql),
+                    false => Err(Error::TooManyStatements(max_prepared)),
+                });
+                match prepared.await? {
+                    Ok(prepared) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_prepared(&mut stream, prepared).await?;
                     }
                     Err(e) => {
-                        write_status(&mut stream, Status::Err(e.to_string())).await?;
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::ExecutePrepared { stmt, params } => {
+                let timeout = args.timeout(None);
+                let executed = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    let executed = conn
+                        .execute_prepared(stmt, &params)
+                        .map_err(|e| conn.timed_out(e));
+                    conn.clear_timeout()?;
+                    executed
+                });
+                match executed.await? {
+                    Ok(changes) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_changes(&mut stream, changes).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::QueryPrepared { stmt, params } => {
+                // The time limit covers streaming the rows too
+                let timeout = args.timeout(None);
+                let opened = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    conn.open_prepared(stmt, &params)
+                        .map_err(|e| conn.timed_out(e))
+                });
+                match opened.await? {
+                    Ok((cursor, columns)) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_columns(&mut stream, &columns).await?;
+                        stream_rows(&mut stream, &mut conn, cursor, args.batch_size).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
                     }
                 };
+                conn.run(|conn| conn.clear_timeout()).await??;
+            }
+            Command::ClosePrepared { stmt } => {
+                match conn.run(move |conn| conn.close_prepared(stmt)).await? {
+                    Ok(_) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::OpenCursor { sql, params } => {
+                let max_cursors = args.max_cursors;
+                let timeout = args.timeout(None);
+                let opened = conn.run(move |conn| {
+                    if conn.cursors() >= max_cursors {
+                        return Err(Error::TooManyCursors(max_cursors));
+                    }
+                    conn.set_timeout(timeout)?;
+                    let opened = conn.open(&sql, &params).map_err(|e| conn.timed_out(e));
+                    conn.clear_timeout()?;
+                    opened
+                });
+                match opened.await? {
+                    Ok((cursor, columns)) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_cursor(&mut stream, Cursor { cursor, columns }).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
             }
-            Command::Transaction { sqls } => match conn.transaction(sqls) {
+            Command::Fetch { cursor, max_rows } => {
+                // Never buffer more than a batch, whatever the client asks for
+                let max_rows = args.batch_size.get().min(max_rows as usize);
+                // The time limit covers each fetch, not the cursor as a whole
+                let timeout = args.timeout(None);
+                let fetched = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    let fetched = conn.fetch(cursor, max_rows).map_err(|e| conn.timed_out(e));
+                    conn.clear_timeout()?;
+                    fetched
+                });
+                match fetched.await? {
+                    Ok((values, done)) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_fetched(&mut stream, Fetched { values, done }).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::CloseCursor { cursor } => {
+                match conn.run(move |conn| conn.close(cursor)).await? {
+                    Ok(_) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::Script { sql } => {
+                let max_rows = args.max_script_rows;
+                let timeout = args.timeout(None);
+                let script = conn.run(move |conn| {
+                    conn.set_timeout(timeout)?;
+                    let script = conn.script(&sql, max_rows);
+                    conn.clear_timeout()?;
+                    Ok::<_, Error>(script)
+                });
+                let script = script.await??;
+                write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                write_script(&mut stream, script).await?;
+            }
+            Command::Begin { mode } => match conn.run(move |conn| conn.begin(mode)).await? {
                 Ok(_) => {
-                    write_status(&mut stream, Status::Ok).await?;
+                    write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
                 }
                 Err(e) => {
-                    write_status(&mut stream, Status::Err(e.to_string())).await?;
+                    write_response(
+                        &mut stream,
+                        Status::Err(e.to_status()),
+                        conn.transaction_state(),
+                    )
+                    .await?;
                 }
             },
+            Command::Commit => match conn.run(move |conn| conn.commit()).await? {
+                Ok(_) => {
+                    write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                }
+                Err(e) => {
+                    write_response(
+                        &mut stream,
+                        Status::Err(e.to_status()),
+                        conn.transaction_state(),
+                    )
+                    .await?;
+                }
+            },
+            Command::Rollback => match conn.run(move |conn| conn.rollback()).await? {
+                Ok(_) => {
+                    write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                }
+                Err(e) => {
+                    write_response(
+                        &mut stream,
+                        Status::Err(e.to_status()),
+                        conn.transaction_state(),
+                    )
+                    .await?;
+                }
+            },
+            Command::Savepoint { name } => match conn.run(move |conn| conn.savepoint(&name)).await?
+            {
+                Ok(_) => {
+                    write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                }
+                Err(e) => {
+                    write_response(
+                        &mut stream,
+                        Status::Err(e.to_status()),
+                        conn.transaction_state(),
+                    )
+                    .await?;
+                }
+            },
+            Command::Release { name } => match conn.run(move |conn| conn.release(&name)).await? {
+                Ok(_) => {
+                    write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                }
+                Err(e) => {
+                    write_response(
+                        &mut stream,
+                        Status::Err(e.to_status()),
+                        conn.transaction_state(),
+                    )
+                    .await?;
+                }
+            },
+            Command::RollbackTo { name } => {
+                match conn.run(move |conn| conn.rollback_to(&name)).await? {
+                    Ok(_) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::SetLimit { limit, value } => {
+                let value = value.map(|value| args.limit(limit, value));
+                let result = conn
+                    .run(move |conn| match value {
+                        Some(value) => conn.set_limit(limit, value),
+                        None => conn.limit(limit),
+                    })
+                    .await?;
+                match result {
+                    Ok(value) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_limit(&mut stream, value).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::SetDbConfig { option, enabled } => {
+                let result = match (enabled, args.pinned(option)) {
+                    (Some(enabled), Some(pinned)) if enabled != pinned => {
+                        Err(Error::DbConfigPinned(option))
+                    }
+                    (Some(enabled), _) => {
+                        conn.run(move |conn| conn.set_db_config(option, enabled))
+                            .await?
+                    }
+                    (None, _) => conn.run(move |conn| conn.db_config(option)).await?,
+                };
+                match result {
+                    Ok(enabled) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                        write_db_config(&mut stream, enabled).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+            Command::LoadExtension { name, entry_point } => {
+                let loaded = match args.extension(&name) {
+                    Some(path) => {
+                        info!(%name, ?entry_point, "Loading extension");
+                        let path = path.to_path_buf();
+                        conn.run(move |conn| conn.load_extension(&path, entry_point.as_deref()))
+                            .await?
+                    }
+                    None => Err(Error::ExtensionNotAllowed(name)),
+                };
+                match loaded {
+                    Ok(_) => {
+                        write_response(&mut stream, Status::Ok, conn.transaction_state()).await?;
+                    }
+                    Err(e) => {
+                        write_response(
+                            &mut stream,
+                            Status::Err(e.to_status()),
+                            conn.transaction_state(),
+                        )
+                        .await?;
+                    }
+                }
+            }
         }
     }
     Ok(())
 }
+
+/// Writes the rows of an open cursor in batches of `batch_size` rows, then closes it
+async fn stream_rows<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufStream<S>,
+    conn: &mut Worker,
+    cursor: u64,
+    batch_size: NonZeroUsize,
+) -> Result<()> {
+    loop {
+        match conn
+            .run(move |conn| conn.fetch(cursor, batch_size.get()))
+            .await?
+        {
+            Ok((values, done)) => {
+                if !values.is_empty() {
+                    write_batch(stream, Batch::Rows(values)).await?;
+                }
+                if done {
+                    break;
+                }
+            }
+            Err(e) => {
+                let e = conn
+                    .run(move |conn| {
+                        conn.close(cursor)?;
+                        Ok::<_, Error>(conn.timed_out(e))
+                    })
+                    .await??;
+                write_batch(stream, Batch::Err(e.to_status())).await?;
+                return Ok(());
+            }
+        }
+    }
+    let summary = conn.run(move |conn| conn.close(cursor)).await??;
+    let batch = Batch::Done {
+        changes: summary.changes,
+        duration: summary.duration.as_millis() as u64,
+    };
+    write_batch(stream, batch).await?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use crate::{Error, Result};
+use glob::{MatchOptions, Pattern};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// `*` and `?` don't match `/` nor a leading `.`, `**` matches any number of directories
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+/// Resolves a database path asked for by a client against the canonical `root` directory,
+/// refusing paths with `..`, resolving out of `root` through symlinks,
+/// or not matching any of the `allow` patterns when there are some
+pub fn resolve(root: &Path, allow: &[Pattern], path: &str) -> Result<String> {
+    // In-memory and temporary databases have no file
+    if path.is_empty() || path == ":memory:" {
+        return Ok(path.to_string());
+    }
+
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(Error::ParentPath(path.to_string()));
+            }
+        }
+    }
+    let Some(name) = relative.file_name() else {
+        return Err(Error::PathNotAllowed(path.to_string()));
+    };
+
+    // Follow the symlinks of the directories, and of the file once it exists
+    let joined = root.join(&relative);
+    let resolved = match joined.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            joined.parent().unwrap_or(root).canonicalize()?.join(name)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let Ok(inside) = resolved.strip_prefix(root) else {
+        return Err(Error::PathEscapesRoot(path.to_string()));
+    };
+    if !allow.is_empty()
+        && !allow
+            .iter()
+            .any(|pattern| pattern.matches_path_with(inside, MATCH_OPTIONS))
+    {
+        return Err(Error::PathNotAllowed(path.to_string()));
+    }
+    resolved
+        .into_os_string()
+        .into_string()
+        .map_err(|_| Error::PathNotAllowed(path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// An empty canonical root directory for the test `name`
+    fn root(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("echolite-sandbox-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<Pattern> {
+        patterns.iter().map(|p| Pattern::new(p).unwrap()).collect()
+    }
+
+    #[test]
+    fn parent_dir() {
+        let root = root("parent");
+        for path in ["../x.db", "a/../x.db", "/../x.db"] {
+            let resolved = resolve(&root, &[], path);
+            assert!(matches!(resolved, Err(Error::ParentPath(_))), "{path}");
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_out_of_root() {
+        use std::os::unix::fs::symlink;
+
+        let root = root("symlink");
+        let outside = self::root("symlink-outside");
+        fs::write(outside.join("secret.db"), b"").unwrap();
+        symlink(&outside, root.join("dir")).unwrap();
+        symlink(outside.join("secret.db"), root.join("file.db")).unwrap();
+
+        for path in ["dir/secret.db", "dir/new.db", "file.db"] {
+            let resolved = resolve(&root, &[], path);
+            assert!(matches!(resolved, Err(Error::PathEscapesRoot(_))), "{path}");
+        }
+        fs::remove_dir_all(root).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn file_not_found() {
+        let root = root("missing");
+        fs::create_dir(root.join("sub")).unwrap();
+        let resolved = resolve(&root, &[], "sub/new.db").unwrap();
+        assert_eq!(Path::new(&resolved), root.join("sub/new.db"));
+        // Absolute paths are taken relative to the root too
+        let resolved = resolve(&root, &[], "/new.db").unwrap();
+        assert_eq!(Path::new(&resolved), root.join("new.db"));
+        assert!(resolve(&root, &[], "nowhere/new.db").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn glob_miss() {
+        let root = root("glob");
+        fs::create_dir_all(root.join("reports/2024")).unwrap();
+        fs::create_dir(root.join("other")).unwrap();
+        let allow = patterns(&["*.db", "reports/**/*.db"]);
+        for path in ["app.db", "reports/2024/q1.db", "reports/q1.db"] {
+            assert!(resolve(&root, &allow, path).is_ok(), "{path}");
+        }
+        for path in [
+            "app.txt",
+            ".hidden.db",
+            "reports/2024/q1.txt",
+            "other/app.db",
+        ] {
+            let resolved = resolve(&root, &allow, path);
+            assert!(matches!(resolved, Err(Error::PathNotAllowed(_))), "{path}");
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+}
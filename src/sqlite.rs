@@ -1,71 +1,830 @@
+use crate::cli::{Ceiling, OpenPolicy, Pin};
 use crate::{Error, Result};
-use protocol::{Column, Flags, Query, Value};
-use rusqlite::{Connection, OpenFlags, types::ValueRef};
-use std::time::Instant;
+use protocol::{
+    Changes, Column, DbConfig, Flags, Limit, Parameters, Prepared, Script, ScriptError,
+    StatementResult, TransactionMode, TransactionState, Value,
+};
+use rusqlite::fallible_iterator::FallibleIterator;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::{
+    Batch, Connection, ErrorCode, InterruptHandle, LoadExtensionGuard, OpenFlags, Rows, Statement,
+    ffi,
+};
+use self_cell::{MutBorrow, self_cell};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+type RowsRef<'stmt> = Rows<'stmt>;
+
+self_cell!(
+    struct Cursor<'conn> {
+        owner: MutBorrow<Statement<'conn>>,
+        #[covariant]
+        dependent: RowsRef,
+    }
+);
+
+/// A statement being stepped through across several calls
+struct OpenCursor<'conn> {
+    cursor: Cursor<'conn>,
+    columns: usize,
+    /// Handle of the prepared statement to give the statement back to once closed
+    prepared: Option<u64>,
+    total_changes: u64,
+    elapsed: Duration,
+}
+
+#[derive(Default)]
+struct State<'conn> {
+    statements: HashMap<u64, Statement<'conn>>,
+    cursors: HashMap<u64, OpenCursor<'conn>>,
+}
+
+self_cell!(
+    struct Session {
+        owner: Connection,
+        #[not_covariant]
+        dependent: State,
+    }
+);
 
-#[derive(Debug)]
 pub struct Sqlite {
-    conn: Connection,
+    session: Session,
+    next_stmt: u64,
+    next_cursor: u64,
+    timeout: Option<Timeout>,
 }
 
-impl Sqlite {
-    pub fn connect(path: &str, flags: Flags) -> Result<Self> {
-        let open = OpenFlags::from_bits(flags.bits()).ok_or_else(|| Error::InvalidFlags)?;
-        let conn = Connection::open_with_flags(path, open)?;
-        Ok(Self { conn })
+/// Time limit of the running command
+#[derive(Debug, Clone, Copy)]
+struct Timeout {
+    timeout: Duration,
+    deadline: Instant,
+    /// Whether the session was in autocommit mode when the command started
+    autocommit: bool,
+}
+
+/// Number of virtual machine instructions between deadline checks
+const PROGRESS_OPS: i32 = 1000;
+
+impl std::fmt::Debug for Sqlite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sqlite")
+            .field("conn", self.conn())
+            .finish_non_exhaustive()
     }
+}
 
-    pub fn query(&self, sql: &str) -> Result<Query> {
-        let t = Instant::now();
-        let mut stmt = self.conn.prepare(sql)?;
+/// Summary of a closed cursor
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub changes: Changes,
+    pub duration: Duration,
+}
 
-        let columns = stmt
-            .columns()
-            .into_iter()
-            .map(|col| Column {
-                name: col.name().into(),
-                datatype: col.decl_type().unwrap_or_default().into(),
-            })
-            .collect::<Vec<_>>();
-
-        let mut rows = stmt.query([])?;
-        let mut values = Vec::new();
-        while let Some(row) = rows.next()? {
-            for i in 0..columns.len() {
-                let v = row.get_ref(i)?;
-                let v = match v {
-                    ValueRef::Null => Value::Null,
-                    ValueRef::Integer(i) => Value::I64(i),
-                    ValueRef::Real(f) => Value::F64(f),
-                    ValueRef::Text(s) => Value::Text(s.to_vec()),
-                    ValueRef::Blob(b) => Value::Bytes(b.to_vec()),
-                };
-                values.push(v);
+impl Sqlite {
+    /// Opens the database at `path` with the `flags` asked for, as restricted by `policy`,
+    /// with limits lowered to their `ceilings` and options set as `pins` pin them,
+    /// the PRAGMAs setting the pinned options being denied.
+    /// A `confined` session may not attach other database files.
+    pub fn connect(
+        path: &str,
+        flags: Flags,
+        policy: OpenPolicy,
+        ceilings: &[Ceiling],
+        pins: &[Pin],
+        confined: bool,
+    ) -> Result<Self> {
+        let effective = policy.apply(path, flags)?;
+        match effective == flags {
+            true => debug!(%effective, "Open flags allowed by policy"),
+            false => info!(requested = %flags, %effective, "Open flags restricted by policy"),
+        }
+        let open = OpenFlags::from_bits(effective.bits()).ok_or_else(|| Error::InvalidFlags)?;
+        let conn = Connection::open_with_flags(path, open)?;
+        let sqlite = Self {
+            session: Session::new(conn, |_| State::default()),
+            next_stmt: 0,
+            next_cursor: 0,
+            timeout: None,
+        };
+        for ceiling in ceilings {
+            if sqlite.limit(ceiling.limit)? > ceiling.value {
+                sqlite.set_limit(ceiling.limit, ceiling.value)?;
             }
         }
+        for pin in pins {
+            sqlite.set_db_config(pin.option, pin.enabled)?;
+        }
+        let pinned: Vec<_> = pins.iter().filter_map(|pin| pragma(pin.option)).collect();
+        if confined || !pinned.is_empty() {
+            sqlite.conn().authorizer(Some(move |ctx: AuthContext| {
+                authorize(ctx, confined, &pinned)
+            }))?;
+        }
+        Ok(sqlite)
+    }
 
-        Ok(Query {
-            columns,
-            values,
-            rows_affected: self.conn.changes(),
-            duration: t.elapsed().as_millis() as u64,
-        })
+    fn conn(&self) -> &Connection {
+        self.session.borrow_owner()
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.conn().get_interrupt_handle()
     }
 
-    pub fn execute(&self, sql: &str) -> Result<()> {
-        self.conn.execute_batch(sql)?;
+    /// Interrupts the statements stepped from now on once `timeout` elapses,
+    /// until [`Sqlite::clear_timeout`] is called
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let Some(timeout) = timeout else {
+            return Ok(());
+        };
+        let deadline = Instant::now() + timeout;
+        self.conn()
+            .progress_handler(PROGRESS_OPS, Some(move || Instant::now() >= deadline))?;
+        self.timeout = Some(Timeout {
+            timeout,
+            deadline,
+            autocommit: self.conn().is_autocommit(),
+        });
+        Ok(())
+    }
+
+    pub fn clear_timeout(&mut self) -> Result<()> {
+        if self.timeout.take().is_some() {
+            self.conn().progress_handler(0, None::<fn() -> bool>)?;
+        }
         Ok(())
     }
 
-    pub fn transaction(&mut self, sqls: Vec<String>) -> Result<()> {
+    /// Turns an interruption past the deadline into [`Error::Timeout`],
+    /// rolling back the transaction begun by the command if any
+    pub fn timed_out(&self, error: Error) -> Error {
+        let Some(timeout) = self.timeout else {
+            return error;
+        };
+        let interrupted = match &error {
+            Error::Sqlite(e) => e.sqlite_error_code() == Some(ErrorCode::OperationInterrupted),
+            _ => false,
+        };
+        if !interrupted || Instant::now() < timeout.deadline {
+            return error;
+        }
+        if timeout.autocommit && !self.conn().is_autocommit() {
+            // The transaction may hold a write lock, don't leave it to the client.
+            // Rolling back only fails if the transaction is over already.
+            let _ = self.conn().execute_batch("ROLLBACK");
+        }
+        Error::Timeout(timeout.timeout.as_millis() as u64)
+    }
+
+    pub fn execute(&self, sql: &str, params: &Parameters) -> Result<Changes> {
+        let conn = self.conn();
+        let total_changes = conn.total_changes();
+        // Without parameters the SQL may be a script of several statements
+        if params.is_empty() {
+            conn.execute_batch(sql)?;
+        } else {
+            let mut stmt = conn.prepare(sql)?;
+            bind(&mut stmt, params)?;
+            stmt.raw_execute()?;
+        }
+        Ok(changes(conn, total_changes))
+    }
+
+    /// Runs every statement of `sql` in order until one fails,
+    /// failing the statement that would take the rows of the script over `max_rows`
+    pub fn script(&self, sql: &str, max_rows: usize) -> Script {
+        let conn = self.conn();
+        let mut results = Vec::new();
+        let mut rows_left = max_rows;
+        for (offset, sql) in split(sql) {
+            let mut run = || -> Result<Option<StatementResult>> {
+                let mut batch = Batch::new(conn, sql);
+                let Some(mut stmt) = batch.next()? else {
+                    // Only whitespace or comments
+                    return Ok(None);
+                };
+                let columns = columns(&stmt);
+                let total_changes = conn.total_changes();
+                let mut rows = stmt.raw_query();
+                let mut values = Vec::new();
+                while let Some(row) = rows.next()? {
+                    if rows_left == 0 {
+                        return Err(Error::TooManyRows(max_rows));
+                    }
+                    rows_left -= 1;
+                    for i in 0..columns.len() {
+                        values.push(to_value(row.get_ref(i)?));
+                    }
+                }
+                if !columns.is_empty() {
+                    return Ok(Some(StatementResult::Rows { columns, values }));
+                }
+                Ok(Some(StatementResult::Execute(changes(conn, total_changes))))
+            };
+            match run() {
+                Ok(Some(result)) => results.push(result),
+                Ok(None) => {}
+                Err(e) => {
+                    let leading = sql.len() - sql.trim_start().len();
+                    let mut error = self.timed_out(e).to_status();
+                    if let Some(at) = error.offset.as_mut() {
+                        *at += offset as u64;
+                    }
+                    let error = ScriptError {
+                        offset: (offset + leading) as u64,
+                        error,
+                    };
+                    return Script {
+                        results,
+                        error: Some(error),
+                    };
+                }
+            }
+        }
+        Script {
+            results,
+            error: None,
+        }
+    }
+
+    pub fn transaction(&mut self, sqls: Vec<String>) -> Result<Vec<Changes>> {
         if sqls.is_empty() {
-            return Ok(());
+            return Ok(Vec::new());
         }
-        let tx = self.conn.transaction()?;
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let mut results = Vec::with_capacity(sqls.len());
         for sql in sqls {
+            let total_changes = conn.total_changes();
             tx.execute(&sql, ())?;
+            results.push(changes(conn, total_changes));
         }
         tx.commit()?;
+        Ok(results)
+    }
+
+    pub fn transaction_state(&self) -> TransactionState {
+        match self.conn().is_autocommit() {
+            true => TransactionState::Idle,
+            false => TransactionState::Active,
+        }
+    }
+
+    pub fn begin(&self, mode: TransactionMode) -> Result<()> {
+        let sql = match mode {
+            TransactionMode::Deferred => "BEGIN DEFERRED",
+            TransactionMode::Immediate => "BEGIN IMMEDIATE",
+            TransactionMode::Exclusive => "BEGIN EXCLUSIVE",
+        };
+        self.conn().execute_batch(sql)?;
+        Ok(())
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        self.conn().execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    pub fn rollback(&self) -> Result<()> {
+        self.conn().execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    pub fn savepoint(&self, name: &str) -> Result<()> {
+        self.conn()
+            .execute_batch(&format!("SAVEPOINT {}", quote(name)))?;
+        Ok(())
+    }
+
+    pub fn release(&self, name: &str) -> Result<()> {
+        self.conn()
+            .execute_batch(&format!("RELEASE SAVEPOINT {}", quote(name)))?;
         Ok(())
     }
+
+    pub fn rollback_to(&self, name: &str) -> Result<()> {
+        self.conn()
+            .execute_batch(&format!("ROLLBACK TO SAVEPOINT {}", quote(name)))?;
+        Ok(())
+    }
+
+    pub fn limit(&self, limit: Limit) -> Result<u64> {
+        let value = self.conn().limit(to_limit(limit))?;
+        Ok(value as u64)
+    }
+
+    /// Sets `limit` to `value`, returning the value in effect,
+    /// as SQLite lowers it to the most it was compiled with
+    pub fn set_limit(&self, limit: Limit, value: u64) -> Result<u64> {
+        let value = value.min(i32::MAX as u64) as i32;
+        self.conn().set_limit(to_limit(limit), value)?;
+        self.limit(limit)
+    }
+
+    pub fn db_config(&self, option: DbConfig) -> Result<bool> {
+        let enabled = self.conn().db_config(to_db_config(option))?;
+        Ok(enabled)
+    }
+
+    /// Turns `option` on or off, returning whether it is on
+    pub fn set_db_config(&self, option: DbConfig, enabled: bool) -> Result<bool> {
+        let enabled = self.conn().set_db_config(to_db_config(option), enabled)?;
+        Ok(enabled)
+    }
+
+    /// Loads the extension at `path`, extension loading being enabled for that call only
+    pub fn load_extension(&self, path: &Path, entry_point: Option<&str>) -> Result<()> {
+        // Safety: the extension is allowed by the operator, and no SQL runs while
+        // loading is enabled as the session only runs one job at a time
+        unsafe {
+            let _guard = LoadExtensionGuard::new(self.conn())?;
+            self.conn().load_extension(path, entry_point)?;
+        }
+        Ok(())
+    }
+
+    pub fn prepare(&mut self, sql: &str) -> Result<Prepared> {
+        let id = self.next_stmt;
+        let parameters = self.session.with_dependent_mut(|conn, state| {
+            let stmt = conn.prepare(sql)?;
+            let parameters = (1..=stmt.parameter_count())
+                .map(|i| stmt.parameter_name(i).map(String::from))
+                .collect();
+            state.statements.insert(id, stmt);
+            Ok::<_, Error>(parameters)
+        })?;
+        self.next_stmt += 1;
+        Ok(Prepared {
+            stmt: id,
+            parameters,
+        })
+    }
+
+    pub fn execute_prepared(&mut self, id: u64, params: &Parameters) -> Result<Changes> {
+        self.session.with_dependent_mut(|conn, state| {
+            let stmt = state
+                .statements
+                .get_mut(&id)
+                .ok_or(Error::UnknownStatement(id))?;
+            let total_changes = conn.total_changes();
+            bind(stmt, params)?;
+            stmt.raw_execute()?;
+            Ok(changes(conn, total_changes))
+        })
+    }
+
+    pub fn close_prepared(&mut self, id: u64) -> Result<()> {
+        match self
+            .session
+            .with_dependent_mut(|_, state| state.statements.remove(&id).is_some())
+        {
+            true => Ok(()),
+            false => Err(Error::UnknownStatement(id)),
+        }
+    }
+
+    /// Prepares `sql` and opens a cursor over its rows
+    pub fn open(&mut self, sql: &str, params: &Parameters) -> Result<(u64, Vec<Column>)> {
+        let id = self.next_cursor;
+        let columns = self.session.with_dependent_mut(|conn, state| {
+            let t = Instant::now();
+            let mut stmt = conn.prepare(sql)?;
+            bind(&mut stmt, params)?;
+            let (cursor, columns) = open_cursor(conn, stmt, None, t);
+            state.cursors.insert(id, cursor);
+            Ok::<_, Error>(columns)
+        })?;
+        self.next_cursor += 1;
+        Ok((id, columns))
+    }
+
+    /// Opens a cursor over the rows of a prepared statement,
+    /// the statement can't be used again until the cursor is closed
+    pub fn open_prepared(&mut self, stmt: u64, params: &Parameters) -> Result<(u64, Vec<Column>)> {
+        let id = self.next_cursor;
+        let columns = self.session.with_dependent_mut(|conn, state| {
+            let t = Instant::now();
+            let mut prepared = state
+                .statements
+                .remove(&stmt)
+                .ok_or(Error::UnknownStatement(stmt))?;
+            if let Err(e) = bind(&mut prepared, params) {
+                state.statements.insert(stmt, prepared);
+                return Err(e);
+            }
+            let (cursor, columns) = open_cursor(conn, prepared, Some(stmt), t);
+            state.cursors.insert(id, cursor);
+            Ok(columns)
+        })?;
+        self.next_cursor += 1;
+        Ok((id, columns))
+    }
+
+    /// Prepared statements of the session, counting those a cursor is open over
+    pub fn statements(&self) -> usize {
+        self.session.with_dependent(|_, state| {
+            let open = state.cursors.values();
+            state.statements.len() + open.filter(|open| open.prepared.is_some()).count()
+        })
+    }
+
+    pub fn cursors(&self) -> usize {
+        self.session.with_dependent(|_, state| state.cursors.len())
+    }
+
+    /// Steps the cursor for up to `max_rows` rows,
+    /// returns their values and whether the cursor is exhausted
+    pub fn fetch(&mut self, id: u64, max_rows: usize) -> Result<(Vec<Value>, bool)> {
+        self.session.with_dependent_mut(|_, state| {
+            let open = state.cursors.get_mut(&id).ok_or(Error::UnknownCursor(id))?;
+            let t = Instant::now();
+            let columns = open.columns;
+            let fetched = open
+                .cursor
+                .with_dependent_mut(|_, rows| -> Result<(Vec<Value>, bool)> {
+                    let mut values = Vec::new();
+                    for _ in 0..max_rows {
+                        let Some(row) = rows.next()? else {
+                            return Ok((values, true));
+                        };
+                        for i in 0..columns {
+                            values.push(to_value(row.get_ref(i)?));
+                        }
+                    }
+                    Ok((values, false))
+                });
+            open.elapsed += t.elapsed();
+            fetched
+        })
+    }
+
+    pub fn close(&mut self, id: u64) -> Result<Summary> {
+        self.session.with_dependent_mut(|conn, state| {
+            let open = state.cursors.remove(&id).ok_or(Error::UnknownCursor(id))?;
+            let stmt = open.cursor.into_owner().into_inner();
+            if let Some(prepared) = open.prepared {
+                state.statements.insert(prepared, stmt);
+            }
+            Ok(Summary {
+                changes: changes(conn, open.total_changes),
+                duration: open.elapsed,
+            })
+        })
+    }
+}
+
+fn open_cursor<'conn>(
+    conn: &Connection,
+    stmt: Statement<'conn>,
+    prepared: Option<u64>,
+    t: Instant,
+) -> (OpenCursor<'conn>, Vec<Column>) {
+    let columns = columns(&stmt);
+    let cursor = Cursor::new(MutBorrow::new(stmt), |stmt| stmt.borrow_mut().raw_query());
+    let open = OpenCursor {
+        cursor,
+        columns: columns.len(),
+        prepared,
+        total_changes: conn.total_changes(),
+        elapsed: t.elapsed(),
+    };
+    (open, columns)
+}
+
+/// Denies attaching database files when `confined`, in-memory and temporary databases aside,
+/// which also denies `VACUUM INTO` as it attaches the file it writes,
+/// and setting the `pinned` PRAGMAs, reading them being fine
+fn authorize(ctx: AuthContext, confined: bool, pinned: &[&str]) -> Authorization {
+    match ctx.action {
+        AuthAction::Attach { filename }
+            if confined && !filename.is_empty() && filename != ":memory:" =>
+        {
+            Authorization::Deny
+        }
+        // SQLite only names the file when it is a string literal,
+        // not when it is an expression or a bound parameter
+        AuthAction::Unknown {
+            code: ffi::SQLITE_ATTACH,
+            ..
+        } if confined => Authorization::Deny,
+        AuthAction::Pragma {
+            pragma_name,
+            pragma_value: Some(_),
+        } if pinned
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(pragma_name)) =>
+        {
+            Authorization::Deny
+        }
+        _ => Authorization::Allow,
+    }
+}
+
+/// Changes of the statements run since `total_changes` was taken
+fn changes(conn: &Connection, total_changes: u64) -> Changes {
+    let now = conn.total_changes();
+    Changes {
+        // `sqlite3_changes` keeps the count of the last INSERT, UPDATE or DELETE
+        // even if no such statement was run since
+        rows_affected: match now > total_changes {
+            true => conn.changes(),
+            false => 0,
+        },
+        total_changes: now,
+        last_insert_rowid: conn.last_insert_rowid(),
+    }
+}
+
+/// Quotes an identifier such as a savepoint name
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Splits a script into its statements and their byte offsets,
+/// cutting at each `;` that ends a complete statement like the sqlite3 shell.
+/// Tokenizes the script once the way `sqlite3_complete` does,
+/// carrying its state from one statement to the next.
+fn split(sql: &str) -> Vec<(usize, &str)> {
+    const SEMI: usize = 0;
+    const WS: usize = 1;
+    const OTHER: usize = 2;
+    const EXPLAIN: usize = 3;
+    const CREATE: usize = 4;
+    const TEMP: usize = 5;
+    const TRIGGER: usize = 6;
+    const END: usize = 7;
+    /// State after each token, the states being invalid, start, normal, explain,
+    /// create, trigger, semi and end, a statement is complete in the start state
+    const TRANS: [[u8; 8]; 8] = [
+        [1, 0, 2, 3, 4, 2, 2, 2],
+        [1, 1, 2, 3, 4, 2, 2, 2],
+        [1, 2, 2, 2, 2, 2, 2, 2],
+        [1, 3, 3, 2, 4, 2, 2, 2],
+        [1, 4, 2, 2, 2, 4, 5, 2],
+        [6, 5, 5, 5, 5, 5, 5, 5],
+        [6, 6, 5, 5, 5, 5, 5, 7],
+        [1, 7, 5, 5, 5, 5, 5, 5],
+    ];
+    const START: u8 = 1;
+    const KEYWORDS: [(&str, usize); 6] = [
+        ("explain", EXPLAIN),
+        ("create", CREATE),
+        ("temp", TEMP),
+        ("temporary", TEMP),
+        ("trigger", TRIGGER),
+        ("end", END),
+    ];
+
+    fn is_id(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80
+    }
+
+    let bytes = sql.as_bytes();
+    // Index of the first `close` from `from`, an unclosed token leaving the rest incomplete
+    let find = |from: usize, close: &[u8]| {
+        bytes
+            .get(from..)?
+            .windows(close.len())
+            .position(|window| window == close)
+            .map(|at| from + at)
+    };
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut state = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let token = match bytes[i] {
+            b';' => SEMI,
+            b' ' | b'\t' | b'\n' | b'\r' | b'\x0c' => WS,
+            b'/' if bytes.get(i + 1) == Some(&b'*') => match find(i + 2, b"*/") {
+                Some(end) => {
+                    i = end + 1;
+                    WS
+                }
+                None => break,
+            },
+            b'-' if bytes.get(i + 1) == Some(&b'-') => match find(i + 2, b"\n") {
+                Some(end) => {
+                    i = end;
+                    WS
+                }
+                None => break,
+            },
+            open @ (b'\'' | b'"' | b'`' | b'[') => {
+                let close = if open == b'[' { b']' } else { open };
+                match find(i + 1, &[close]) {
+                    Some(end) => {
+                        i = end;
+                        OTHER
+                    }
+                    None => break,
+                }
+            }
+            c if is_id(c) => {
+                let len = bytes[i..].iter().take_while(|&&c| is_id(c)).count();
+                let word = &sql[i..i + len];
+                i += len - 1;
+                KEYWORDS
+                    .iter()
+                    .find(|(keyword, _)| word.eq_ignore_ascii_case(keyword))
+                    .map_or(OTHER, |&(_, token)| token)
+            }
+            _ => OTHER,
+        };
+        i += 1;
+        state = TRANS[state as usize][token];
+        if token == SEMI && state == START {
+            statements.push((start, &sql[start..i]));
+            start = i;
+        }
+    }
+    if !sql[start..].trim().is_empty() {
+        statements.push((start, &sql[start..]));
+    }
+    statements
+}
+
+fn columns(stmt: &Statement) -> Vec<Column> {
+    stmt.columns()
+        .into_iter()
+        .map(|col| Column {
+            name: col.name().into(),
+            datatype: col.decl_type().unwrap_or_default().into(),
+        })
+        .collect()
+}
+
+fn bind(stmt: &mut Statement, params: &Parameters) -> Result<()> {
+    stmt.clear_bindings();
+    match params {
+        Parameters::Positional(values) => {
+            let expected = stmt.parameter_count();
+            if values.len() != expected {
+                return Err(rusqlite::Error::InvalidParameterCount(values.len(), expected).into());
+            }
+            for (i, value) in values.iter().enumerate() {
+                stmt.raw_bind_parameter(i + 1, to_sql(value))?;
+            }
+        }
+        Parameters::Named(values) => {
+            for (name, value) in values {
+                let i = stmt
+                    .parameter_index(name)?
+                    .ok_or_else(|| rusqlite::Error::InvalidParameterName(name.clone()))?;
+                stmt.raw_bind_parameter(i, to_sql(value))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn to_sql(value: &Value) -> ToSqlOutput<'_> {
+    ToSqlOutput::Borrowed(match value {
+        Value::Null => ValueRef::Null,
+        Value::I64(i) => ValueRef::Integer(*i),
+        Value::F64(f) => ValueRef::Real(*f),
+        Value::Text(s) => ValueRef::Text(s),
+        Value::Bytes(b) => ValueRef::Blob(b),
+    })
+}
+
+fn to_value(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::I64(i),
+        ValueRef::Real(f) => Value::F64(f),
+        ValueRef::Text(s) => Value::Text(s.to_vec()),
+        ValueRef::Blob(b) => Value::Bytes(b.to_vec()),
+    }
+}
+
+fn to_limit(limit: Limit) -> rusqlite::limits::Limit {
+    use rusqlite::limits::Limit::*;
+    match limit {
+        Limit::Length => SQLITE_LIMIT_LENGTH,
+        Limit::SqlLength => SQLITE_LIMIT_SQL_LENGTH,
+        Limit::Column => SQLITE_LIMIT_COLUMN,
+        Limit::ExprDepth => SQLITE_LIMIT_EXPR_DEPTH,
+        Limit::CompoundSelect => SQLITE_LIMIT_COMPOUND_SELECT,
+        Limit::VdbeOp => SQLITE_LIMIT_VDBE_OP,
+        Limit::FunctionArg => SQLITE_LIMIT_FUNCTION_ARG,
+        Limit::Attached => SQLITE_LIMIT_ATTACHED,
+        Limit::LikePatternLength => SQLITE_LIMIT_LIKE_PATTERN_LENGTH,
+        Limit::VariableNumber => SQLITE_LIMIT_VARIABLE_NUMBER,
+        Limit::TriggerDepth => SQLITE_LIMIT_TRIGGER_DEPTH,
+        Limit::WorkerThreads => SQLITE_LIMIT_WORKER_THREADS,
+    }
+}
+
+fn to_db_config(option: DbConfig) -> rusqlite::config::DbConfig {
+    use rusqlite::config::DbConfig::*;
+    match option {
+        DbConfig::EnableFkey => SQLITE_DBCONFIG_ENABLE_FKEY,
+        DbConfig::EnableTrigger => SQLITE_DBCONFIG_ENABLE_TRIGGER,
+        DbConfig::EnableView => SQLITE_DBCONFIG_ENABLE_VIEW,
+        DbConfig::Defensive => SQLITE_DBCONFIG_DEFENSIVE,
+        DbConfig::TrustedSchema => SQLITE_DBCONFIG_TRUSTED_SCHEMA,
+        DbConfig::DqsDml => SQLITE_DBCONFIG_DQS_DML,
+        DbConfig::DqsDdl => SQLITE_DBCONFIG_DQS_DDL,
+    }
+}
+
+/// The PRAGMA setting `option` as well, if any
+fn pragma(option: DbConfig) -> Option<&'static str> {
+    match option {
+        DbConfig::EnableFkey => Some("foreign_keys"),
+        DbConfig::TrustedSchema => Some("trusted_schema"),
+        DbConfig::EnableTrigger
+        | DbConfig::EnableView
+        | DbConfig::Defensive
+        | DbConfig::DqsDml
+        | DbConfig::DqsDdl => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    /// Splits like the sqlite3 shell, calling `sqlite3_complete` at each `;`
+    fn split_complete(sql: &str) -> Vec<(usize, &str)> {
+        let complete = |sql: &str| {
+            let sql = CString::new(sql).unwrap();
+            // SAFETY: `sql` is a NUL-terminated string that outlives the call
+            unsafe { rusqlite::ffi::sqlite3_complete(sql.as_ptr()) != 0 }
+        };
+        let mut statements = Vec::new();
+        let mut start = 0;
+        for (end, _) in sql.match_indices(';') {
+            if complete(&sql[start..=end]) {
+                statements.push((start, &sql[start..=end]));
+                start = end + 1;
+            }
+        }
+        if !sql[start..].trim().is_empty() {
+            statements.push((start, &sql[start..]));
+        }
+        statements
+    }
+
+    #[test]
+    fn confined_attach() {
+        let dir = std::env::temp_dir().join(format!("echolite-attach-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir
+            .join("attached.db")
+            .into_os_string()
+            .into_string()
+            .unwrap();
+        let (head, tail) = file.split_at(file.len() - 3);
+
+        let flags = Flags::default();
+        let sqlite =
+            Sqlite::connect(":memory:", flags, OpenPolicy::default(), &[], &[], true).unwrap();
+        let none = Parameters::default();
+        let path = Parameters::Positional(vec![Value::Text(file.clone().into_bytes())]);
+        let denied = [
+            (format!("ATTACH '{file}' AS a"), &none),
+            (format!("ATTACH '{head}' || '{tail}' AS a"), &none),
+            ("ATTACH ? AS a".to_string(), &path),
+            (format!("VACUUM INTO '{file}'"), &none),
+            ("VACUUM INTO ?".to_string(), &path),
+        ];
+        for (sql, params) in denied {
+            assert!(sqlite.execute(&sql, params).is_err(), "{sql}");
+            assert!(!Path::new(&file).exists(), "{sql}");
+        }
+        for sql in ["ATTACH '' AS t", "ATTACH ':memory:' AS m", "VACUUM"] {
+            sqlite.execute(sql, &none).unwrap();
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn split_like_sqlite3_complete() {
+        let scripts = [
+            "",
+            " ; ;",
+            "SELECT 1; SELECT 2",
+            "SELECT 1;\n-- comment; still\nSELECT 2; -- trailing;",
+            "SELECT 'a;b', \"c;d\", `e;f`, [g;h]; SELECT 'it''s;'",
+            "SELECT 1 /* ; */; /* unclosed ;",
+            "SELECT 'unclosed; SELECT 2;",
+            "CREATE TRIGGER t AFTER INSERT ON x BEGIN SELECT 1; SELECT 2; END; SELECT 3;",
+            "create temporary trigger t after insert on x begin select 1; end ; select 2",
+            "EXPLAIN CREATE TEMP TRIGGER t AFTER INSERT ON x BEGIN SELECT 1; END; SELECT 4",
+            "CREATE TABLE end_(trigger_); SELECT end FROM t; SELECT $end, _temp;",
+            "SELECT 'é;'; SELECT \u{1F600}; SELECT 1",
+        ];
+        for sql in scripts {
+            assert_eq!(split(sql), split_complete(sql), "{sql:?}");
+        }
+    }
 }
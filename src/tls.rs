@@ -0,0 +1,188 @@
+use crate::cli::ClientIdentity;
+use crate::{Error, Result};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Builds the acceptor wrapping accepted connections in TLS,
+/// from a PEM certificate chain and its PEM private key.
+/// With a PEM `client_ca` bundle, clients must present a certificate issued by it
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let certs = pem_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| Error::Pem(key.into(), e))?;
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let config = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in pem_certs(client_ca)? {
+                roots.add(cert)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+    };
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn pem_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Pem(path.into(), e))
+}
+
+/// The user identity named by a verified client certificate chain, if it has one
+pub fn identity(certs: &[CertificateDer], field: ClientIdentity) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(certs.first()?).ok()?;
+    match field {
+        ClientIdentity::Cn => cert
+            .subject()
+            .iter_common_name()
+            .find_map(|cn| cn.as_str().ok())
+            .map(str::to_string),
+        ClientIdentity::San => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, DnType, IsCa, Issuer, KeyPair,
+        generate_simple_self_signed,
+    };
+    use std::fs;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+    /// An empty directory for the PEM files of the test `name`
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("echolite-tls-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a self-signed certificate for `localhost` and its key, returning their paths
+    fn self_signed(dir: &Path) -> (PathBuf, PathBuf) {
+        let CertifiedKey { cert, signing_key } =
+            generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_file, cert.pem()).unwrap();
+        fs::write(&key_file, signing_key.serialize_pem()).unwrap();
+        (cert_file, key_file)
+    }
+
+    #[tokio::test]
+    async fn self_signed_server() {
+        let dir = dir("self-signed");
+        let (cert, key) = self_signed(&dir);
+        let acceptor = acceptor(&cert, &key, None).unwrap();
+        let tls = client::Tls::new(&cert, "localhost").unwrap();
+
+        let (client, server) = duplex(16 * 1024);
+        let (client, server) = tokio::join!(tls.connect(client), acceptor.accept(server));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(server.get_ref().1.peer_certificates().is_none());
+
+        // The certificate isn't valid for another name
+        let tls = client::Tls::new(&cert, "example.com").unwrap();
+        let (client, server) = duplex(16 * 1024);
+        let (client, _) = tokio::join!(tls.connect(client), acceptor.accept(server));
+        assert!(client.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mutual() {
+        let dir = dir("mutual");
+        let (cert, key) = self_signed(&dir);
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_file = dir.join("ca.pem");
+        fs::write(&ca_file, ca_params.self_signed(&ca_key).unwrap().pem()).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(["alice.example".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        let client_cert = params.signed_by(&client_key, &issuer).unwrap();
+        let (client_cert_file, client_key_file) = (dir.join("client.pem"), dir.join("client.key"));
+        fs::write(&client_cert_file, client_cert.pem()).unwrap();
+        fs::write(&client_key_file, client_key.serialize_pem()).unwrap();
+
+        let acceptor = acceptor(&cert, &key, Some(&ca_file)).unwrap();
+        let tls =
+            client::Tls::with_client_cert(&cert, "localhost", &client_cert_file, &client_key_file)
+                .unwrap();
+        let (client, server) = duplex(16 * 1024);
+        let (client, server) = tokio::join!(tls.connect(client), acceptor.accept(server));
+        assert!(client.is_ok());
+        let server = server.unwrap();
+        let certs = server.get_ref().1.peer_certificates().unwrap();
+        assert_eq!(
+            identity(certs, ClientIdentity::Cn).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            identity(certs, ClientIdentity::San).as_deref(),
+            Some("alice.example")
+        );
+
+        // Clients without a certificate, or with one the CA didn't issue, are refused
+        let strangers = [
+            client::Tls::new(&cert, "localhost").unwrap(),
+            client::Tls::with_client_cert(&cert, "localhost", &cert, &key).unwrap(),
+        ];
+        for tls in strangers {
+            let (client, server) = duplex(16 * 1024);
+            let (_, server) = tokio::join!(tls.connect(client), acceptor.accept(server));
+            assert!(server.is_err());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_pem() {
+        let dir = dir("invalid-pem");
+        let (cert, key) = self_signed(&dir);
+        let empty = dir.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        assert!(matches!(acceptor(&cert, &empty, None), Err(Error::Pem(..))));
+        assert!(matches!(
+            acceptor(&dir.join("missing.pem"), &key, None),
+            Err(Error::Pem(..))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use crate::{Error, Result};
+use std::fs::{self, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use tokio::net::UnixListener;
+use tracing::warn;
+
+/// Binds a Unix domain socket at `path` with the permissions `mode`,
+/// first removing the socket file of a server that is no longer running
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::NotSocket(path.into()));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => return Err(Error::SocketInUse(path.into())),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                warn!("Removing stale socket file {}", path.display());
+                fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    // The socket file is created with the permissions left by the umask, so
+    // mask out everything `mode` doesn't grant until the mode is set below
+    // SAFETY: umask can't fail, and the listener is bound before any session
+    // starts, with no other thread creating files in the meantime
+    let umask = unsafe { libc::umask((!mode & 0o777) as libc::mode_t) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
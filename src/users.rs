@@ -0,0 +1,211 @@
+use crate::Error;
+use crate::cli::Verified;
+use crate::verifier::Verifier;
+use blake2::digest::consts::U16;
+use blake2::digest::{Digest, Mac};
+use blake2::{Blake2b512, Blake2bMac};
+use protocol::{Flags, Params, Prehash, Salt, consts::*};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// What a user may do with the databases it opens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Databases are always opened read-only
+    ReadOnly,
+    ReadWrite,
+    /// Read-write, and not limited to the listed databases.
+    /// Other users may not attach database files.
+    Admin,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+    #[serde(default)]
+    users: Vec<UserEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    name: String,
+    password: String,
+    role: Role,
+    #[serde(default)]
+    databases: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    databases: Vec<String>,
+    verifier: Verifier,
+}
+
+impl User {
+    /// Salt and params the password of the user is hashed with
+    pub fn prehash(&self) -> &Prehash {
+        self.verifier.prehash()
+    }
+
+    pub fn allows(&self, path: &str) -> bool {
+        self.unconfined() || self.databases.iter().any(|database| database == path)
+    }
+
+    /// Whether the user may open any database, and so attach any database file
+    pub fn unconfined(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// The open flags the user is held to, given the ones asked for by the client
+    pub fn flags(&self, mut flags: Flags) -> Flags {
+        if self.role == Role::ReadOnly {
+            flags.set(SQLITE_OPEN_READWRITE, false);
+            flags.set(SQLITE_OPEN_CREATE, false);
+            flags.set(SQLITE_OPEN_READONLY, true);
+        }
+        flags
+    }
+
+    pub async fn verify(
+        &self,
+        client_salt: Salt,
+        server_salt: Salt,
+        params: Params,
+        mutual: bool,
+        client_password: [u8; 32],
+    ) -> Result<Verified, Error> {
+        self.verifier
+            .verify(client_salt, server_salt, params, mutual, client_password)
+            .await
+    }
+}
+
+/// Users allowed to connect, loaded from a TOML users file
+#[derive(Debug)]
+pub struct Users {
+    users: HashMap<String, Arc<User>>,
+    decoy: DecoyKey,
+    /// Params of the decoy prehash, the ones most users have
+    params: Params,
+}
+
+impl Users {
+    pub fn load(path: &str) -> Result<Arc<Self>, String> {
+        let text = std::fs::read_to_string(Path::new(path))
+            .map_err(|e| format!("Cannot read users file `{}`: {}", path, e))?;
+        let file: UsersFile = toml::from_str(&text)
+            .map_err(|e| format!("Cannot parse users file `{}`: {}", path, e))?;
+        let mut users = HashMap::new();
+        // Users per params, in the order of the file so that ties are broken the same way
+        let mut counts: Vec<(Params, usize)> = Vec::new();
+        for entry in file.users {
+            let user = Self::user(entry)?;
+            if users.contains_key(&user.name) {
+                return Err(format!("Duplicate user `{}`", user.name));
+            }
+            let params = user.prehash().params;
+            match counts.iter_mut().find(|(counted, _)| *counted == params) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((params, 1)),
+            }
+            users.insert(user.name.clone(), Arc::new(user));
+        }
+        let params = counts
+            .into_iter()
+            .max_by_key(|&(_, count)| count)
+            .map_or_else(Params::default, |(params, _)| params);
+        Ok(Arc::new(Self {
+            users,
+            decoy: DecoyKey::new(&text),
+            params,
+        }))
+    }
+
+    fn user(entry: UserEntry) -> Result<User, String> {
+        let verifier = Verifier::parse(&entry.password)
+            .map_err(|e| format!("Invalid password of user `{}`: {}", entry.name, e))?;
+        // Decoy salts are as long, so the length doesn't tell known users apart
+        let salt = verifier.prehash().salt.len();
+        if salt != size_of::<Salt>() {
+            return Err(format!(
+                "Invalid password of user `{}`: salt is {} bytes long, expected {}",
+                entry.name,
+                salt,
+                size_of::<Salt>()
+            ));
+        }
+        Ok(User {
+            name: entry.name,
+            role: entry.role,
+            databases: entry.databases,
+            verifier,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<User>> {
+        self.users.get(name).cloned()
+    }
+
+    /// Prehash to send for an unknown user, the same on every attempt and across restarts
+    /// so that unknown users can't be told apart from known ones,
+    /// at least from the users with the most common params
+    pub fn decoy(&self, name: &str) -> Prehash {
+        let mut mac = Blake2bMac::<U16>::new_from_slice(&self.decoy.0)
+            .expect("BLAKE2b takes keys of up to 64 bytes");
+        mac.update(name.as_bytes());
+        let salt = mac.finalize().into_bytes().to_vec();
+        Prehash {
+            salt,
+            params: self.params,
+        }
+    }
+}
+
+/// Key of the decoy salts, derived from the users file so that the salt of an unknown user
+/// stays the same across restarts like the ones of known users, and is as secret as the file
+#[derive(Zeroize, ZeroizeOnDrop)]
+struct DecoyKey([u8; 64]);
+
+impl DecoyKey {
+    fn new(users_file: &str) -> Self {
+        let key = Blake2b512::new_with_prefix(b"echolite decoy salt")
+            .chain_update(users_file)
+            .finalize();
+        DecoyKey(key.into())
+    }
+}
+
+impl std::fmt::Debug for DecoyKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DecoyKey")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoy_stable_across_loads() {
+        let path = std::env::temp_dir().join(format!("echolite-users-{}.toml", std::process::id()));
+        std::fs::write(&path, "users = []\n").unwrap();
+        let path = path.to_str().unwrap();
+        let (first, second) = (Users::load(path).unwrap(), Users::load(path).unwrap());
+        let decoy = first.decoy("alice");
+        assert_eq!(decoy, second.decoy("alice"));
+        assert_eq!(decoy.salt.len(), size_of::<Salt>());
+        assert_eq!(decoy.params, Params::default());
+        assert_ne!(decoy.salt, first.decoy("bob").salt);
+
+        std::fs::write(path, "users = []\n\n").unwrap();
+        assert_ne!(decoy.salt, Users::load(path).unwrap().decoy("alice").salt);
+        std::fs::remove_file(path).unwrap();
+    }
+}
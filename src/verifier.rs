@@ -0,0 +1,105 @@
+use crate::Error;
+use crate::cli::{Verified, verify};
+use argon2::password_hash::{Output, PasswordHash, SaltString};
+use protocol::{HashedPassword, Params, Prehash, Salt, to_prehash_password};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// The Argon2id hash of a password, standing in for the password in the login challenge.
+/// The client hashes the password with the same salt and params before answering it,
+/// so the hash is enough to answer it and must be kept as secret as the password.
+#[derive(Debug)]
+pub struct Verifier {
+    prehash: Prehash,
+    hash: SecureHash,
+}
+
+#[derive(Zeroize, ZeroizeOnDrop)]
+struct SecureHash(HashedPassword);
+
+impl std::fmt::Debug for SecureHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecureHash")
+    }
+}
+
+impl Verifier {
+    /// Parses a 32-byte Argon2id version 19 hash in the PHC string format
+    pub fn parse(phc: &str) -> Result<Self, String> {
+        let hash = PasswordHash::new(phc).map_err(|e| e.to_string())?;
+        if hash.algorithm != argon2::ARGON2ID_IDENT {
+            return Err("not an Argon2id hash".into());
+        }
+        if hash.version.is_some_and(|version| version != 0x13) {
+            return Err("not an Argon2 version 19 hash".into());
+        }
+        let params = argon2::Params::try_from(&hash).map_err(|e| e.to_string())?;
+        let mut salt = [0; 64];
+        let salt = hash
+            .salt
+            .ok_or("missing salt")?
+            .decode_b64(&mut salt)
+            .map_err(|e| e.to_string())?
+            .to_vec();
+        let output = hash.hash.ok_or("missing hash")?;
+        let hash =
+            HashedPassword::try_from(output.as_bytes()).map_err(|_| "hash is not 32 bytes long")?;
+        Ok(Verifier {
+            prehash: Prehash {
+                salt,
+                params: Params {
+                    m_cost: params.m_cost(),
+                    t_cost: params.t_cost(),
+                    p_cost: params.p_cost(),
+                },
+            },
+            hash: SecureHash(hash),
+        })
+    }
+
+    /// Salt and params the password is hashed with
+    pub fn prehash(&self) -> &Prehash {
+        &self.prehash
+    }
+
+    pub async fn verify(
+        &self,
+        client_salt: Salt,
+        server_salt: Salt,
+        params: Params,
+        mutual: bool,
+        client_password: [u8; 32],
+    ) -> Result<Verified, Error> {
+        verify(
+            &self.hash.0,
+            client_salt,
+            server_salt,
+            params,
+            mutual,
+            client_password,
+        )
+        .await
+    }
+}
+
+/// Hashes `password` with a random salt into the PHC string of a [`Verifier`]
+pub async fn hash_password(password: &str, params: Params) -> Result<String, String> {
+    let salt = protocol::rand_salt();
+    let prehash = Prehash {
+        salt: salt.to_vec(),
+        params,
+    };
+    let mut hash = to_prehash_password(password, &prehash)
+        .await
+        .map_err(|e| e.to_string())?;
+    let output = Output::new(&hash).map_err(|e| e.to_string());
+    hash.zeroize();
+    let salt = SaltString::encode_b64(&salt).map_err(|e| e.to_string())?;
+    Ok(format!(
+        "$argon2id$v=19$m={},t={},p={}${}${}",
+        params.m_cost,
+        params.t_cost,
+        params.p_cost,
+        salt.as_str(),
+        output?
+    ))
+}
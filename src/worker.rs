@@ -0,0 +1,84 @@
+use crate::cli::{Ceiling, OpenPolicy, Pin};
+use crate::sqlite::Sqlite;
+use crate::{Error, Result};
+use protocol::{Flags, TransactionState};
+use rusqlite::InterruptHandle;
+use std::sync::mpsc;
+use std::thread;
+use tokio::sync::oneshot;
+use tracing::Span;
+
+type Job = Box<dyn FnOnce(&mut Sqlite) + Send>;
+
+/// A session's database connection, living on a dedicated thread
+/// so that SQLite never blocks the async runtime
+#[derive(Debug)]
+pub struct Worker {
+    jobs: mpsc::Sender<Job>,
+    state: TransactionState,
+}
+
+impl Worker {
+    /// Opens the database on a new thread, which exits once the worker is dropped,
+    /// see [`Sqlite::connect`].
+    /// The thread logs within the span of the caller.
+    pub async fn connect(
+        path: String,
+        flags: Flags,
+        policy: OpenPolicy,
+        ceilings: Vec<Ceiling>,
+        pins: Vec<Pin>,
+        confined: bool,
+    ) -> Result<(Self, InterruptHandle)> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (sender, connected) = oneshot::channel();
+        let span = Span::current();
+        thread::Builder::new()
+            .name("echolite-session".into())
+            .spawn(move || {
+                let _span = span.enter();
+                let mut sqlite =
+                    match Sqlite::connect(&path, flags, policy, &ceilings, &pins, confined) {
+                        Ok(sqlite) => {
+                            let _ = sender.send(Ok(sqlite.interrupt_handle()));
+                            sqlite
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e));
+                            return;
+                        }
+                    };
+                for job in receiver {
+                    job(&mut sqlite);
+                }
+            })?;
+        let handle = connected.await.map_err(|_| Error::Worker)??;
+        let worker = Self {
+            jobs,
+            state: TransactionState::Idle,
+        };
+        Ok((worker, handle))
+    }
+
+    /// Runs `f` on the worker thread, failing only if the thread is gone
+    pub async fn run<F, T>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Sqlite) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, done) = oneshot::channel();
+        let job: Job = Box::new(move |sqlite| {
+            let value = f(sqlite);
+            let _ = sender.send((value, sqlite.transaction_state()));
+        });
+        self.jobs.send(job).map_err(|_| Error::Worker)?;
+        let (value, state) = done.await.map_err(|_| Error::Worker)?;
+        self.state = state;
+        Ok(value)
+    }
+
+    /// Transaction state of the session as of the last job
+    pub fn transaction_state(&self) -> TransactionState {
+        self.state
+    }
+}